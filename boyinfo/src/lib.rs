@@ -0,0 +1,425 @@
+//! Boot information handed from boyloader to boykernel.
+//!
+//! Everything in here is `#[repr(C)]` and only made of plain integers and enums
+//! with an explicit representation, so both sides agree on the layout no matter
+//! how each crate is compiled. Pointers are stored as physical addresses (`u64`)
+//! since the kernel decides how memory is mapped once it takes over.
+#![no_std]
+
+use core::fmt;
+
+/// "BOYKISS!" in little-endian, written first so garbage is easy to spot.
+pub const BOOT_INFO_MAGIC: u64 = u64::from_le_bytes(*b"BOYKISS!");
+
+/// Bumped every time the layout of [`BootInfo`] changes.
+pub const BOOT_INFO_VERSION: u32 = 8;
+
+/// Signature of the kernel entry point. The boot info pointer is passed in `rdi`.
+pub type KernelEntry = extern "sysv64" fn(&'static mut BootInfo) -> !;
+
+/// Which bits of a pixel hold each channel.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelMasks {
+    pub red: u32,
+    pub green: u32,
+    pub blue: u32,
+    pub reserved: u32,
+}
+
+/// Pixel layout of the framebuffer, mirroring the GOP pixel formats.
+#[repr(C, u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// 32-bit pixels with red in the lowest byte.
+    Rgb = 0,
+    /// 32-bit pixels with blue in the lowest byte.
+    Bgr = 1,
+    /// Channels described by the masks.
+    Bitmask(PixelMasks) = 2,
+    /// No linear framebuffer, the firmware only supports drawing through `Blt()`.
+    BltOnly = 3,
+}
+
+impl PixelFormat {
+    /// Channel masks of this format, `None` without a linear framebuffer.
+    pub fn masks(&self) -> Option<PixelMasks> {
+        match *self {
+            PixelFormat::Rgb => Some(PixelMasks {
+                red: 0x0000_00FF,
+                green: 0x0000_FF00,
+                blue: 0x00FF_0000,
+                reserved: 0xFF00_0000,
+            }),
+            PixelFormat::Bgr => Some(PixelMasks {
+                red: 0x00FF_0000,
+                green: 0x0000_FF00,
+                blue: 0x0000_00FF,
+                reserved: 0xFF00_0000,
+            }),
+            PixelFormat::Bitmask(masks) => Some(masks),
+            PixelFormat::BltOnly => None,
+        }
+    }
+
+    /// Size of a pixel in bits, `None` without a linear framebuffer.
+    pub fn bits_per_pixel(&self) -> Option<u32> {
+        let masks = self.masks()?;
+        let used = masks.red | masks.green | masks.blue | masks.reserved;
+        Some((32 - used.leading_zeros()).next_multiple_of(8))
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FramebufferInfo {
+    /// Physical address of the framebuffer, 0 if there is none.
+    pub address: u64,
+    pub size: usize,
+    pub width: usize,
+    pub height: usize,
+    pub stride: usize,
+    pub format: PixelFormat,
+}
+
+impl FramebufferInfo {
+    /// Whether the kernel can draw to this framebuffer, which only handles 32-bit pixels.
+    pub fn is_usable(&self) -> bool {
+        self.address != 0 && self.format.bits_per_pixel() == Some(32)
+    }
+}
+
+/// What a [`MemoryRegion`] may be used for, as decided by the loader.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryRegionKind {
+    /// Free RAM.
+    Usable = 0,
+    /// Anything the kernel must never touch (runtime services, ACPI NVS, holes...).
+    Reserved = 1,
+    /// ACPI tables, free to reuse once they have been parsed.
+    AcpiReclaimable = 2,
+    /// The kernel image itself.
+    LoaderCode = 3,
+    /// Boot info, memory map and anything else boyloader handed over.
+    LoaderData = 4,
+    /// Memory-mapped I/O.
+    Mmio = 5,
+    /// Firmware boot services code/data. Free after ExitBootServices, but it still
+    /// holds the firmware page tables and the stack the kernel was entered on.
+    BootServices = 6,
+}
+
+/// A single entry of the firmware memory map.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryRegion {
+    pub kind: MemoryRegionKind,
+    /// Raw UEFI memory type (`EFI_MEMORY_TYPE`), kept for diagnostics.
+    pub firmware_type: u32,
+    pub phys_start: u64,
+    /// Number of 4 KiB pages in this region.
+    pub page_count: u64,
+    /// Raw UEFI attribute bits (`EFI_MEMORY_*`).
+    pub attributes: u64,
+}
+
+impl MemoryRegion {
+    pub const PAGE_SIZE: u64 = 4096;
+
+    pub fn phys_end(&self) -> u64 {
+        self.phys_start + self.page_count * Self::PAGE_SIZE
+    }
+}
+
+/// Array of [`MemoryRegion`]s allocated by the loader.
+#[repr(C)]
+#[derive(Debug)]
+pub struct MemoryMap {
+    pub regions: u64,
+    pub len: u64,
+    /// Firmware descriptors left out because the array was full. Memory they
+    /// describe is unknown to the kernel, so anything but 0 is worth a warning.
+    pub dropped: u64,
+}
+
+impl MemoryMap {
+    pub fn regions(&self) -> &[MemoryRegion] {
+        if self.regions == 0 {
+            return &[];
+        }
+        // SAFETY: the loader allocates the array as LOADER_DATA and never frees it.
+        unsafe {
+            core::slice::from_raw_parts(self.regions as *const MemoryRegion, self.len as usize)
+        }
+    }
+}
+
+/// Most loadable segments a kernel ELF may have.
+pub const MAX_KERNEL_SEGMENTS: usize = 8;
+
+/// Access rights of a [`KernelSegment`], using the ELF `p_flags` bits.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentFlags(pub u32);
+
+impl SegmentFlags {
+    pub const EXECUTE: Self = Self(1);
+    pub const WRITE: Self = Self(2);
+    pub const READ: Self = Self(4);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// Prints the flags `ls`-style, e.g. `r-x`.
+impl fmt::Display for SegmentFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |set: Self, c: char| if self.contains(set) { c } else { '-' };
+        write!(
+            f,
+            "{}{}{}",
+            flag(Self::READ, 'r'),
+            flag(Self::WRITE, 'w'),
+            flag(Self::EXECUTE, 'x')
+        )
+    }
+}
+
+/// A loadable segment of the kernel, page aligned on both ends.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct KernelSegment {
+    pub phys_start: u64,
+    pub virt_start: u64,
+    /// Size in bytes, a multiple of 4 KiB.
+    pub size: u64,
+    pub flags: SegmentFlags,
+}
+
+impl KernelSegment {
+    pub const EMPTY: Self = Self {
+        phys_start: 0,
+        virt_start: 0,
+        size: 0,
+        flags: SegmentFlags(0),
+    };
+}
+
+/// Where the kernel image ended up, both physically and at its link address.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct KernelImage {
+    pub phys_start: u64,
+    pub phys_end: u64,
+    pub virt_start: u64,
+    pub virt_end: u64,
+    /// How far the kernel was moved from its link address, `virt_start` minus the
+    /// lowest `p_vaddr`. Subtract it from runtime addresses to look them up in the ELF.
+    pub slide: u64,
+    /// Non-zero if `slide` was chosen at random (KASLR).
+    pub randomized: u64,
+    pub segment_count: u64,
+    /// Mapping description of every loaded segment, the first `segment_count` are valid.
+    pub segments: [KernelSegment; MAX_KERNEL_SEGMENTS],
+}
+
+impl KernelImage {
+    pub fn segments(&self) -> &[KernelSegment] {
+        &self.segments[..(self.segment_count as usize).min(MAX_KERNEL_SEGMENTS)]
+    }
+}
+
+/// A function from the kernel ELF's `.symtab`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct KernelSymbol {
+    /// Link address, subtract [`KernelImage::slide`] from a runtime address first.
+    pub address: u64,
+    pub size: u64,
+    /// Where the demangled name starts in [`KernelSymbols::names`].
+    pub name_offset: u32,
+    pub name_len: u32,
+}
+
+/// Function symbols of the kernel sorted by address, empty if the ELF was stripped.
+#[repr(C)]
+#[derive(Debug)]
+pub struct KernelSymbols {
+    /// Array of [`KernelSymbol`]s.
+    pub symbols: u64,
+    pub count: u64,
+    /// UTF-8 names of all symbols, back to back.
+    pub names: u64,
+    pub names_len: u64,
+}
+
+impl KernelSymbols {
+    pub fn symbols(&self) -> &[KernelSymbol] {
+        if self.symbols == 0 {
+            return &[];
+        }
+        // SAFETY: the loader allocates the array as LOADER_DATA and never frees it.
+        unsafe {
+            core::slice::from_raw_parts(self.symbols as *const KernelSymbol, self.count as usize)
+        }
+    }
+
+    pub fn name(&self, symbol: &KernelSymbol) -> &str {
+        if self.names == 0 {
+            return "";
+        }
+        // SAFETY: same as for the symbols.
+        let names = unsafe {
+            core::slice::from_raw_parts(self.names as *const u8, self.names_len as usize)
+        };
+        let start = symbol.name_offset as usize;
+        names
+            .get(start..start + symbol.name_len as usize)
+            .and_then(|name| core::str::from_utf8(name).ok())
+            .unwrap_or("")
+    }
+
+    /// The symbol containing the link address `address` and the offset into it.
+    pub fn lookup(&self, address: u64) -> Option<(&str, u64)> {
+        let symbols = self.symbols();
+        let index = symbols.partition_point(|symbol| symbol.address <= address);
+        let symbol = symbols.get(index.checked_sub(1)?)?;
+        let offset = address - symbol.address;
+        // Symbols without a size (from assembly) cover everything up to the next one.
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+        Some((self.name(symbol), offset))
+    }
+}
+
+/// UTF-8 command line, not null terminated.
+#[repr(C)]
+#[derive(Debug)]
+pub struct CommandLine {
+    pub address: u64,
+    pub len: u64,
+}
+
+impl CommandLine {
+    pub fn as_str(&self) -> &str {
+        if self.address == 0 {
+            return "";
+        }
+        // SAFETY: the loader copies the command line into LOADER_DATA memory.
+        let bytes =
+            unsafe { core::slice::from_raw_parts(self.address as *const u8, self.len as usize) };
+        core::str::from_utf8(bytes).unwrap_or("")
+    }
+
+    /// Whether the whitespace separated word `flag` appears on the command line.
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.as_str().split_whitespace().any(|arg| arg == flag)
+    }
+
+    /// The value of the last `key=value` word on the command line.
+    pub fn value(&self, key: &str) -> Option<&str> {
+        self.as_str()
+            .split_whitespace()
+            .rev()
+            .filter_map(|arg| arg.split_once('='))
+            .find(|(name, _)| *name == key)
+            .map(|(_, value)| value)
+    }
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct BootInfo {
+    pub magic: u64,
+    pub version: u32,
+    /// `size_of::<BootInfo>()` as seen by the loader.
+    pub size: u32,
+    pub framebuffer: FramebufferInfo,
+    pub memory_map: MemoryMap,
+    /// Physical address of the ACPI RSDP, or 0 if the firmware has none. This is the
+    /// ACPI 2.0 RSDP whenever the firmware offers one, check its revision byte.
+    pub rsdp_address: u64,
+    /// Physical address of the SMBIOS entry point, or 0 if the firmware has none.
+    /// The 64-bit `_SM3_` one is preferred over the 32-bit `_SM_` one.
+    pub smbios_address: u64,
+    pub kernel: KernelImage,
+    pub symbols: KernelSymbols,
+    pub cmdline: CommandLine,
+}
+
+impl BootInfo {
+    /// Moves every physical address the kernel dereferences through this struct by
+    /// `offset`, once physical memory is only reachable through an offset mapping.
+    ///
+    /// # Safety
+    ///
+    /// Must be called exactly once, and `offset` must map all of the loader's data.
+    pub unsafe fn rebase(&mut self, offset: u64) {
+        if self.memory_map.regions != 0 {
+            self.memory_map.regions += offset;
+        }
+        if self.cmdline.address != 0 {
+            self.cmdline.address += offset;
+        }
+        if self.symbols.symbols != 0 {
+            self.symbols.symbols += offset;
+        }
+        if self.symbols.names != 0 {
+            self.symbols.names += offset;
+        }
+    }
+
+    /// Checks that the loader and the kernel were built against the same layout.
+    pub fn validate(&self) -> Result<(), BootInfoError> {
+        if self.magic != BOOT_INFO_MAGIC {
+            return Err(BootInfoError::BadMagic(self.magic));
+        }
+        if self.version != BOOT_INFO_VERSION {
+            return Err(BootInfoError::VersionMismatch {
+                found: self.version,
+                expected: BOOT_INFO_VERSION,
+            });
+        }
+        if self.size as usize != core::mem::size_of::<BootInfo>() {
+            return Err(BootInfoError::SizeMismatch {
+                found: self.size,
+                expected: core::mem::size_of::<BootInfo>() as u32,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum BootInfoError {
+    BadMagic(u64),
+    VersionMismatch { found: u32, expected: u32 },
+    SizeMismatch { found: u32, expected: u32 },
+}
+
+impl fmt::Display for BootInfoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BootInfoError::BadMagic(magic) => {
+                write!(
+                    f,
+                    "bad boot info magic 0x{:016x} (is boyloader outdated?)",
+                    magic
+                )
+            }
+            BootInfoError::VersionMismatch { found, expected } => write!(
+                f,
+                "boot info version {} but the kernel expects version {}",
+                found, expected
+            ),
+            BootInfoError::SizeMismatch { found, expected } => write!(
+                f,
+                "boot info is {} bytes but the kernel expects {} bytes",
+                found, expected
+            ),
+        }
+    }
+}
@@ -0,0 +1,709 @@
+use core::fmt;
+
+use alloc::{format, vec::Vec};
+use log::{error, info, warn};
+use spin::Once;
+use x86_64::PhysAddr;
+
+use crate::paging::{CacheType, map_mmio, phys_to_virt, translate};
+
+const HEADER_SIZE: usize = 36;
+const RSDP_V1_SIZE: usize = 20;
+const RSDP_V2_SIZE: usize = 36;
+
+/// Everything boykernel understood from the firmware's ACPI tables.
+pub static ACPI: Once<AcpiInfo> = Once::new();
+
+#[derive(Debug)]
+pub enum AcpiError {
+    /// The loader found no RSDP in the UEFI configuration tables.
+    NoRsdp,
+    BadSignature {
+        address: u64,
+        expected: &'static str,
+    },
+    BadChecksum {
+        signature: [u8; 4],
+        address: u64,
+    },
+    Truncated {
+        signature: [u8; 4],
+        address: u64,
+    },
+}
+
+impl fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AcpiError::NoRsdp => write!(f, "the firmware did not provide an RSDP"),
+            AcpiError::BadSignature { address, expected } => {
+                write!(f, "no {} signature at 0x{:x}", expected, address)
+            }
+            AcpiError::BadChecksum { signature, address } => {
+                write!(
+                    f,
+                    "{} at 0x{:x} has a bad checksum",
+                    ascii(signature),
+                    address
+                )
+            }
+            AcpiError::Truncated { signature, address } => {
+                write!(f, "{} at 0x{:x} is too short", ascii(signature), address)
+            }
+        }
+    }
+}
+
+/// The `System Description Table Header` fields worth keeping from every table.
+#[derive(Debug, Clone, Copy)]
+pub struct TableHeader {
+    pub signature: [u8; 4],
+    pub address: u64,
+    pub length: u32,
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+}
+
+/// An ACPI Generic Address Structure, describing a register in some address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const SYSTEM_MEMORY: u8 = 0;
+    pub const SYSTEM_IO: u8 = 1;
+
+    fn parse(bytes: &[u8], offset: usize) -> Option<Self> {
+        let address = u64_at(bytes, offset + 4);
+        (address != 0).then(|| Self {
+            address_space: u8_at(bytes, offset),
+            bit_width: u8_at(bytes, offset + 1),
+            bit_offset: u8_at(bytes, offset + 2),
+            access_size: u8_at(bytes, offset + 3),
+            address,
+        })
+    }
+}
+
+impl fmt::Display for GenericAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.address_space {
+            Self::SYSTEM_MEMORY => write!(f, "mem 0x{:x}", self.address)?,
+            Self::SYSTEM_IO => write!(f, "port 0x{:x}", self.address)?,
+            space => write!(f, "space {} 0x{:x}", space, self.address)?,
+        }
+        write!(f, " ({} bits)", self.bit_width)
+    }
+}
+
+/// Interrupt polarity from the MPS INTI flags of MADT entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    /// Whatever the bus specifies, active high for ISA.
+    BusDefault,
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// Interrupt trigger mode from the MPS INTI flags of MADT entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    /// Whatever the bus specifies, edge for ISA.
+    BusDefault,
+    Edge,
+    Level,
+}
+
+fn inti_flags(flags: u16) -> (Polarity, TriggerMode) {
+    let polarity = match flags & 0b11 {
+        0b01 => Polarity::ActiveHigh,
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::BusDefault,
+    };
+    let trigger = match (flags >> 2) & 0b11 {
+        0b01 => TriggerMode::Edge,
+        0b11 => TriggerMode::Level,
+        _ => TriggerMode::BusDefault,
+    };
+    (polarity, trigger)
+}
+
+/// A CPU from a Processor Local APIC or Processor Local x2APIC entry.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    pub processor_uid: u32,
+    pub apic_id: u32,
+    pub enabled: bool,
+    /// Disabled, but can be brought online later.
+    pub online_capable: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    /// First global system interrupt this I/O APIC handles.
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ that is not wired to the GSI of the same number.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub source: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+/// A GSI that must be delivered as an NMI.
+#[derive(Debug, Clone, Copy)]
+pub struct NmiSource {
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+/// A local APIC LINT pin that is wired to NMI.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicNmi {
+    /// Processor UID the entry applies to, `None` for all of them.
+    pub processor_uid: Option<u32>,
+    pub lint: u8,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+/// The Multiple APIC Description Table.
+#[derive(Debug, Default)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    /// The machine also has the legacy 8259 PICs, which must be masked.
+    pub pcat_compat: bool,
+    pub local_apics: Vec<LocalApic>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+    pub nmi_sources: Vec<NmiSource>,
+    pub local_apic_nmis: Vec<LocalApicNmi>,
+}
+
+impl Madt {
+    fn parse(bytes: &[u8]) -> Self {
+        let mut madt = Madt {
+            local_apic_address: u32_at(bytes, 36) as u64,
+            pcat_compat: u32_at(bytes, 40) & 1 != 0,
+            ..Madt::default()
+        };
+
+        let mut offset = 44;
+        while offset + 2 <= bytes.len() {
+            let kind = bytes[offset];
+            let len = bytes[offset + 1] as usize;
+            if len < 2 || offset + len > bytes.len() {
+                warn!("MADT entry of type {} has a bad length", kind);
+                break;
+            }
+            let entry = &bytes[offset..offset + len];
+            offset += len;
+
+            match kind {
+                0 => {
+                    let flags = u32_at(entry, 4);
+                    madt.local_apics.push(LocalApic {
+                        processor_uid: u8_at(entry, 2) as u32,
+                        apic_id: u8_at(entry, 3) as u32,
+                        enabled: flags & 1 != 0,
+                        online_capable: flags & 2 != 0,
+                    });
+                }
+                1 => madt.io_apics.push(IoApic {
+                    id: u8_at(entry, 2),
+                    address: u32_at(entry, 4),
+                    gsi_base: u32_at(entry, 8),
+                }),
+                2 => {
+                    let (polarity, trigger) = inti_flags(u16_at(entry, 8));
+                    madt.overrides.push(InterruptOverride {
+                        source: u8_at(entry, 3),
+                        gsi: u32_at(entry, 4),
+                        polarity,
+                        trigger,
+                    });
+                }
+                3 => {
+                    let (polarity, trigger) = inti_flags(u16_at(entry, 2));
+                    madt.nmi_sources.push(NmiSource {
+                        gsi: u32_at(entry, 4),
+                        polarity,
+                        trigger,
+                    });
+                }
+                4 => {
+                    let uid = u8_at(entry, 2);
+                    let (polarity, trigger) = inti_flags(u16_at(entry, 3));
+                    madt.local_apic_nmis.push(LocalApicNmi {
+                        processor_uid: (uid != 0xFF).then_some(uid as u32),
+                        lint: u8_at(entry, 5),
+                        polarity,
+                        trigger,
+                    });
+                }
+                5 => madt.local_apic_address = u64_at(entry, 4),
+                9 => {
+                    let flags = u32_at(entry, 8);
+                    madt.local_apics.push(LocalApic {
+                        processor_uid: u32_at(entry, 12),
+                        apic_id: u32_at(entry, 4),
+                        enabled: flags & 1 != 0,
+                        online_capable: flags & 2 != 0,
+                    });
+                }
+                10 => {
+                    let uid = u32_at(entry, 4);
+                    let (polarity, trigger) = inti_flags(u16_at(entry, 2));
+                    madt.local_apic_nmis.push(LocalApicNmi {
+                        processor_uid: (uid != u32::MAX).then_some(uid),
+                        lint: u8_at(entry, 8),
+                        polarity,
+                        trigger,
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        madt
+    }
+
+    /// CPUs that are running or can be started.
+    pub fn usable_cpus(&self) -> impl Iterator<Item = &LocalApic> {
+        self.local_apics
+            .iter()
+            .filter(|cpu| cpu.enabled || cpu.online_capable)
+    }
+}
+
+/// The High Precision Event Timer table.
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    pub event_timer_block_id: u32,
+    pub address: GenericAddress,
+    pub hpet_number: u8,
+    /// Smallest periodic tick the HPET can do without losing interrupts, in main
+    /// counter ticks.
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        Some(Hpet {
+            event_timer_block_id: u32_at(bytes, 36),
+            address: GenericAddress::parse(bytes, 40)?,
+            hpet_number: u8_at(bytes, 52),
+            minimum_tick: u16_at(bytes, 53),
+        })
+    }
+}
+
+/// The parts of the Fixed ACPI Description Table boykernel cares about.
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    pub revision: u8,
+    pub dsdt_address: u64,
+    pub sci_interrupt: u16,
+    pub smi_command_port: u32,
+    pub pm_timer: Option<GenericAddress>,
+    /// The ACPI PM timer counts with 32 bits instead of 24.
+    pub pm_timer_32bit: bool,
+    /// CMOS index of the RTC century register, 0 if there is none.
+    pub century_register: u8,
+    /// `IAPC_BOOT_ARCH` flags.
+    pub boot_arch: u16,
+    pub flags: u32,
+    /// Register and value that reset the machine.
+    pub reset: Option<(GenericAddress, u8)>,
+}
+
+impl Fadt {
+    /// `IAPC_BOOT_ARCH`: there is no CMOS RTC.
+    pub const NO_CMOS_RTC: u16 = 1 << 5;
+    const TMR_VAL_EXT: u32 = 1 << 8;
+    const RESET_REG_SUP: u32 = 1 << 10;
+
+    fn parse(bytes: &[u8]) -> Self {
+        let flags = u32_at(bytes, 112);
+        let dsdt_address = match u64_at(bytes, 140) {
+            0 => u32_at(bytes, 40) as u64,
+            address => address,
+        };
+        let pm_timer = GenericAddress::parse(bytes, 208).or_else(|| {
+            let port = u32_at(bytes, 76);
+            (port != 0).then_some(GenericAddress {
+                address_space: GenericAddress::SYSTEM_IO,
+                bit_width: 32,
+                bit_offset: 0,
+                access_size: 3,
+                address: port as u64,
+            })
+        });
+        let reset = if flags & Self::RESET_REG_SUP != 0 {
+            GenericAddress::parse(bytes, 116).map(|register| (register, u8_at(bytes, 128)))
+        } else {
+            None
+        };
+
+        Fadt {
+            revision: u8_at(bytes, 8),
+            dsdt_address,
+            sci_interrupt: u16_at(bytes, 46),
+            smi_command_port: u32_at(bytes, 48),
+            pm_timer,
+            pm_timer_32bit: flags & Self::TMR_VAL_EXT != 0,
+            century_register: u8_at(bytes, 108),
+            boot_arch: u16_at(bytes, 109),
+            flags,
+            reset,
+        }
+    }
+}
+
+/// A PCI Express ECAM window from the MCFG table.
+#[derive(Debug, Clone, Copy)]
+pub struct McfgEntry {
+    pub base_address: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+fn parse_mcfg(bytes: &[u8]) -> Vec<McfgEntry> {
+    bytes
+        .get(44..)
+        .unwrap_or(&[])
+        .chunks_exact(16)
+        .map(|entry| McfgEntry {
+            base_address: u64_at(entry, 0),
+            segment: u16_at(entry, 8),
+            start_bus: u8_at(entry, 10),
+            end_bus: u8_at(entry, 11),
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+pub struct AcpiInfo {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    /// Every table found through the root table and the FADT, root table first.
+    pub tables: Vec<TableHeader>,
+    pub madt: Option<Madt>,
+    pub hpet: Option<Hpet>,
+    pub fadt: Option<Fadt>,
+    pub mcfg: Vec<McfgEntry>,
+}
+
+/// Fields past the end of a table read as zero, which is what ACPI specifies for the
+/// fields newer revisions appended.
+fn read<const N: usize>(bytes: &[u8], offset: usize) -> [u8; N] {
+    bytes
+        .get(offset..offset + N)
+        .and_then(|field| field.try_into().ok())
+        .unwrap_or([0; N])
+}
+
+fn u8_at(bytes: &[u8], offset: usize) -> u8 {
+    bytes.get(offset).copied().unwrap_or(0)
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(read(bytes, offset))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(read(bytes, offset))
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(read(bytes, offset))
+}
+
+fn ascii(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes).unwrap_or("????")
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Gives access to `len` bytes of firmware memory at `address`.
+///
+/// ACPI tables normally sit in memory covered by the physical memory map, but some
+/// firmware puts them in ranges the memory map leaves out, which get mapped on demand.
+fn phys_slice(address: u64, len: usize) -> &'static [u8] {
+    let phys = PhysAddr::new(address);
+    let virt = phys_to_virt(phys);
+    let last = virt + (len.max(1) as u64 - 1);
+    let start = if translate(virt).is_some() && translate(last).is_some() {
+        virt
+    } else {
+        map_mmio(phys, len, CacheType::WriteBack)
+    };
+    unsafe { core::slice::from_raw_parts(start.as_ptr(), len) }
+}
+
+/// Maps the table at `address` and checks its length and checksum.
+fn load_table(address: u64) -> Result<(TableHeader, &'static [u8]), AcpiError> {
+    let header = phys_slice(address, HEADER_SIZE);
+    let signature = read(header, 0);
+    let length = u32_at(header, 4);
+    if (length as usize) < HEADER_SIZE {
+        return Err(AcpiError::Truncated { signature, address });
+    }
+
+    let bytes = phys_slice(address, length as usize);
+    if !checksum_ok(bytes) {
+        return Err(AcpiError::BadChecksum { signature, address });
+    }
+
+    let header = TableHeader {
+        signature,
+        address,
+        length,
+        revision: u8_at(bytes, 8),
+        oem_id: read(bytes, 10),
+        oem_table_id: read(bytes, 16),
+    };
+    Ok((header, bytes))
+}
+
+/// Walks the XSDT (or the RSDT on ACPI 1.0 machines) from the RSDP at `rsdp_address`
+/// and decodes the tables boykernel knows about.
+///
+/// Tables with a bad checksum are reported and skipped, only a broken RSDP or root
+/// table is an error.
+pub fn parse_acpi(rsdp_address: u64) -> Result<AcpiInfo, AcpiError> {
+    if rsdp_address == 0 {
+        return Err(AcpiError::NoRsdp);
+    }
+
+    let rsdp = phys_slice(rsdp_address, RSDP_V2_SIZE);
+    if &rsdp[..8] != b"RSD PTR " {
+        return Err(AcpiError::BadSignature {
+            address: rsdp_address,
+            expected: "RSD PTR",
+        });
+    }
+    // ACPI 2.0 extended the RSDP, the original checksum only covers the 1.0 part.
+    let revision = u8_at(rsdp, 15);
+    let oem_id = read(rsdp, 9);
+    let checksummed = match revision {
+        0 | 1 => RSDP_V1_SIZE,
+        _ => RSDP_V2_SIZE,
+    };
+    if !checksum_ok(&rsdp[..RSDP_V1_SIZE]) || !checksum_ok(&rsdp[..checksummed]) {
+        return Err(AcpiError::BadChecksum {
+            signature: *b"RSDP",
+            address: rsdp_address,
+        });
+    }
+
+    let xsdt = if revision >= 2 { u64_at(rsdp, 24) } else { 0 };
+    let (root_address, entry_size, root_signature) = match xsdt {
+        0 => (u32_at(rsdp, 16) as u64, 4, "RSDT"),
+        xsdt => (xsdt, 8, "XSDT"),
+    };
+    let (root_header, root_bytes) = load_table(root_address)?;
+    if root_header.signature != root_signature.as_bytes() {
+        return Err(AcpiError::BadSignature {
+            address: root_address,
+            expected: root_signature,
+        });
+    }
+
+    let mut acpi = AcpiInfo {
+        revision,
+        oem_id,
+        tables: Vec::from([root_header]),
+        madt: None,
+        hpet: None,
+        fadt: None,
+        mcfg: Vec::new(),
+    };
+
+    for entry in root_bytes[HEADER_SIZE..].chunks_exact(entry_size) {
+        let address = match entry_size {
+            8 => u64_at(entry, 0),
+            _ => u32_at(entry, 0) as u64,
+        };
+        if address == 0 {
+            continue;
+        }
+        let (header, bytes) = match load_table(address) {
+            Ok(table) => table,
+            Err(err) => {
+                warn!("Skipping ACPI table: {}", err);
+                continue;
+            }
+        };
+        acpi.tables.push(header);
+
+        match &header.signature {
+            b"APIC" => acpi.madt = Some(Madt::parse(bytes)),
+            b"HPET" => acpi.hpet = Hpet::parse(bytes),
+            b"FACP" => acpi.fadt = Some(Fadt::parse(bytes)),
+            b"MCFG" => acpi.mcfg = parse_mcfg(bytes),
+            _ => {}
+        }
+    }
+
+    // The DSDT is only referenced from the FADT.
+    if let Some(dsdt) = acpi.fadt.map(|fadt| fadt.dsdt_address).filter(|&a| a != 0) {
+        match load_table(dsdt) {
+            Ok((header, _)) => acpi.tables.push(header),
+            Err(err) => warn!("Skipping ACPI table: {}", err),
+        }
+    }
+
+    Ok(acpi)
+}
+
+/// Parses the ACPI tables and stores the result in [`ACPI`]. Without usable tables
+/// the kernel carries on with the defaults of a standard PC.
+pub fn init_acpi(rsdp_address: u64) {
+    match parse_acpi(rsdp_address) {
+        Ok(acpi) => {
+            let cpus = acpi
+                .madt
+                .as_ref()
+                .map_or(1, |madt| madt.usable_cpus().count());
+            info!(
+                "ACPI revision {}, {} tables, {} CPUs",
+                acpi.revision,
+                acpi.tables.len(),
+                cpus
+            );
+            ACPI.call_once(|| acpi);
+        }
+        Err(err) => error!("No usable ACPI tables: {}", err),
+    }
+}
+
+/// The MADT, if the firmware has one.
+pub fn madt() -> Option<&'static Madt> {
+    ACPI.get().and_then(|acpi| acpi.madt.as_ref())
+}
+
+/// Writes everything that was found in the ACPI tables to serial, for the
+/// `acpi.dump` kernel command line flag.
+pub fn dump() {
+    let Some(acpi) = ACPI.get() else {
+        info!("ACPI: no tables");
+        return;
+    };
+
+    info!(
+        "ACPI revision {}, OEM \"{}\"",
+        acpi.revision,
+        ascii(&acpi.oem_id)
+    );
+    for table in &acpi.tables {
+        info!(
+            "  {} at 0x{:x}, {} bytes, revision {}, OEM \"{}\" \"{}\"",
+            ascii(&table.signature),
+            table.address,
+            table.length,
+            table.revision,
+            ascii(&table.oem_id),
+            ascii(&table.oem_table_id)
+        );
+    }
+
+    if let Some(madt) = &acpi.madt {
+        info!(
+            "MADT: local APIC at 0x{:x}{}",
+            madt.local_apic_address,
+            if madt.pcat_compat {
+                ", 8259 PICs present"
+            } else {
+                ""
+            }
+        );
+        for cpu in &madt.local_apics {
+            info!(
+                "  CPU uid {} APIC id {}{}",
+                cpu.processor_uid,
+                cpu.apic_id,
+                match (cpu.enabled, cpu.online_capable) {
+                    (true, _) => "",
+                    (false, true) => " (offline)",
+                    (false, false) => " (disabled)",
+                }
+            );
+        }
+        for io_apic in &madt.io_apics {
+            info!(
+                "  I/O APIC id {} at 0x{:x}, GSI base {}",
+                io_apic.id, io_apic.address, io_apic.gsi_base
+            );
+        }
+        for irq in &madt.overrides {
+            info!(
+                "  IRQ {} -> GSI {} ({:?}, {:?})",
+                irq.source, irq.gsi, irq.polarity, irq.trigger
+            );
+        }
+        for nmi in &madt.nmi_sources {
+            info!(
+                "  NMI on GSI {} ({:?}, {:?})",
+                nmi.gsi, nmi.polarity, nmi.trigger
+            );
+        }
+        for nmi in &madt.local_apic_nmis {
+            let cpu = match nmi.processor_uid {
+                Some(uid) => format!("CPU uid {}", uid),
+                None => "all CPUs".into(),
+            };
+            info!(
+                "  NMI on LINT{} of {} ({:?}, {:?})",
+                nmi.lint, cpu, nmi.polarity, nmi.trigger
+            );
+        }
+    }
+
+    if let Some(hpet) = &acpi.hpet {
+        info!(
+            "HPET {}: {}, block id 0x{:x}, minimum tick {}",
+            hpet.hpet_number, hpet.address, hpet.event_timer_block_id, hpet.minimum_tick
+        );
+    }
+
+    if let Some(fadt) = &acpi.fadt {
+        info!(
+            "FADT revision {}: SCI IRQ {}, SMI port 0x{:x}, boot flags 0x{:x}, flags 0x{:x}",
+            fadt.revision, fadt.sci_interrupt, fadt.smi_command_port, fadt.boot_arch, fadt.flags
+        );
+        if let Some(pm_timer) = &fadt.pm_timer {
+            info!(
+                "  PM timer: {}, {} bits",
+                pm_timer,
+                if fadt.pm_timer_32bit { 32 } else { 24 }
+            );
+        }
+        if let Some((register, value)) = &fadt.reset {
+            info!("  Reset: write 0x{:x} to {}", value, register);
+        }
+        info!("  RTC century register: 0x{:x}", fadt.century_register);
+    }
+
+    for entry in &acpi.mcfg {
+        info!(
+            "MCFG: segment {} buses {}-{} at 0x{:x}",
+            entry.segment, entry.start_bus, entry.end_bus, entry.base_address
+        );
+    }
+}
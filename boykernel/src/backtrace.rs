@@ -0,0 +1,94 @@
+use core::arch::asm;
+
+use boyinfo::KernelImage;
+use x86_64::VirtAddr;
+
+use crate::{
+    boot_info::BOOT_INFO,
+    exceptions::{fatal_println, read_u64_checked},
+    paging::KERNEL_VIRT_BASE,
+};
+
+/// Most frames printed, in case the frame pointer chain loops.
+const MAX_FRAMES: usize = 32;
+
+/// The address `address` has in the kernel ELF, or `None` if it is not kernel code.
+///
+/// The kernel runs from its [`KERNEL_VIRT_BASE`] alias, but relocated pointers still
+/// point at its slid link address, so both are accepted.
+fn link_address(image: &KernelImage, address: u64) -> Option<u64> {
+    if address < KERNEL_VIRT_BASE {
+        return (image.virt_start..image.virt_end)
+            .contains(&address)
+            .then(|| address - image.slide);
+    }
+    let phys = address - KERNEL_VIRT_BASE + image.phys_start;
+    let segment = image
+        .segments()
+        .iter()
+        .find(|segment| (segment.phys_start..segment.phys_start + segment.size).contains(&phys))?;
+    Some(segment.virt_start + (phys - segment.phys_start) - image.slide)
+}
+
+/// Prints one frame as `function+offset`, or just the address without a symbol.
+///
+/// Return addresses point after the call, which may already be the next function,
+/// so they are looked up one byte earlier.
+fn print_frame(index: usize, address: u64, is_return_address: bool) {
+    let adjust = is_return_address as u64;
+    let symbol = BOOT_INFO.get().and_then(|boot_info| {
+        let link = link_address(&boot_info.kernel, address.wrapping_sub(adjust))?;
+        boot_info.symbols.lookup(link)
+    });
+    match symbol {
+        Some((name, offset)) => fatal_println(format_args!(
+            "  #{:<2} {:#018x} {}+{:#x}",
+            index,
+            address,
+            name,
+            offset + adjust
+        )),
+        None => fatal_println(format_args!("  #{:<2} {:#018x} <unknown>", index, address)),
+    }
+}
+
+/// Follows the saved frame pointers starting at `rbp`, printing every return address.
+fn walk_frames(mut rbp: u64, first_index: usize) {
+    for index in first_index..MAX_FRAMES {
+        // Paging starts the kernel with a zero frame pointer, which ends the chain.
+        if rbp == 0 {
+            return;
+        }
+        let read = |addr: u64| VirtAddr::try_new(addr).ok().and_then(read_u64_checked);
+        let (Some(next), Some(return_address)) = (read(rbp), read(rbp + 8)) else {
+            fatal_println(format_args!("  <bad frame pointer {:#x}>", rbp));
+            return;
+        };
+        if return_address == 0 {
+            return;
+        }
+        print_frame(index, return_address, true);
+        // Callers' frames are further up the stack, anything else is a corrupt chain.
+        if next <= rbp {
+            return;
+        }
+        rbp = next;
+    }
+    fatal_println(format_args!("  ..."));
+}
+
+/// Prints the backtrace of code interrupted at `rip` with frame pointer `rbp`.
+pub fn print_backtrace(rip: u64, rbp: u64) {
+    fatal_println(format_args!("Backtrace:"));
+    print_frame(0, rip, false);
+    walk_frames(rbp, 1);
+}
+
+/// Prints the backtrace of whoever called this.
+#[inline(never)]
+pub fn print_current_backtrace() {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    fatal_println(format_args!("Backtrace:"));
+    walk_frames(rbp, 0);
+}
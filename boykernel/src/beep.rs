@@ -1,6 +1,6 @@
 use x86::io::{inb, outb};
 
-use crate::utils::sleep;
+use crate::time::sleep;
 
 const PIT_CHANNEL2: u16 = 0x42;
 const PIT_COMMAND: u16 = 0x43;
@@ -1,18 +1,43 @@
 use lazy_static::lazy_static;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
-
-use crate::{get_and_lock_renderer, info};
-
-const APIC_BASE_PHYS: usize = 0xFEE00000;
-pub static mut APIC_BASE: *mut u32 = APIC_BASE_PHYS as *mut u32;
+use log::info;
+use x86_64::{
+    PhysAddr,
+    registers::model_specific::Msr,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+};
+
+use crate::{
+    acpi::{Polarity, madt},
+    exceptions::install_exception_handlers,
+    ioapic::{IRQ_ENTRIES, IRQ_VECTOR_BASE},
+    kprintln,
+    paging::{CacheType, map_mmio},
+    time::{TIMER_VECTOR, init_time, tick},
+};
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_ID: isize = 0x20;
+const APIC_EOI: isize = 0xB0;
+const LVT_LINT0: isize = 0x350;
+const LVT_LINT1: isize = 0x360;
+/// LVT delivery mode NMI.
+const LVT_NMI: u32 = 0x400;
+const LVT_ACTIVE_LOW: u32 = 1 << 13;
+const APIC_ICR_LOW: isize = 0x300;
+/// ICR destination shorthand "all excluding self", delivery mode NMI.
+const ICR_NMI_ALL_BUT_SELF: u32 = (0b11 << 18) | LVT_NMI;
+
+pub static mut APIC_BASE: *mut u32 = core::ptr::null_mut();
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(interrupt_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt[32].set_handler_fn(timer_interrupt_handler);
+        install_exception_handlers(&mut idt);
+        idt[TIMER_VECTOR].set_handler_fn(timer_interrupt_handler);
         idt[42].set_handler_fn(test_interrupt_handler);
+        for (irq, &handler) in IRQ_ENTRIES.iter().enumerate() {
+            idt[IRQ_VECTOR_BASE + irq as u8].set_handler_fn(handler);
+        }
         idt[255].set_handler_fn(spurious_interrupt_handler); // Register spurious interrupt handler
         idt
     };
@@ -20,34 +45,21 @@ lazy_static! {
 
 // Add a spurious interrupt handler
 extern "x86-interrupt" fn spurious_interrupt_handler(stack_frame: InterruptStackFrame) {
-    info("Spurious interrupt occurred");
+    info!("Spurious interrupt occurred");
     let _ = stack_frame;
 }
 
 extern "x86-interrupt" fn test_interrupt_handler(stack_frame: InterruptStackFrame) {
-    info("Test interrupt occurred!");
+    info!("Test interrupt occurred!");
     let _ = stack_frame;
 }
 
 pub fn test_interrupts() {
-    info("Testing interrupts...");
+    kprintln!("Testing interrupts...");
     unsafe {
         core::arch::asm!("int 42", options(nostack));
     }
-}
-
-extern "x86-interrupt" fn interrupt_handler(stack_frame: InterruptStackFrame) {
-    info("Interrupt occurred");
-    let _ = stack_frame;
-}
-
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    info("Page fault occurred");
-    let _ = stack_frame;
-    let _ = error_code;
+    kprintln!("Interrupts work");
 }
 
 pub fn init_idt() {
@@ -56,7 +68,7 @@ pub fn init_idt() {
 
 /// Disable the PIC
 pub fn disable_pic() {
-    info("Disabling PIC...");
+    info!("Disabling PIC...");
     unsafe {
         // Mask all interrupts on both PICs
         core::arch::asm!(
@@ -68,57 +80,108 @@ pub fn disable_pic() {
     }
 }
 
-// Add a function to register NMI sources for ACPI
-pub fn register_nmi_sources() {
-    info("Registering NMI sources...");
-    unsafe {
-        // Example: Configure LINT1 as NMI
-        let lint1_reg = APIC_BASE.offset(0x350 / 4);
-        core::ptr::write_volatile(lint1_reg, 0x400); // Set delivery mode to NMI
+/// Physical address of the local APIC, from the MADT or else the APIC base MSR.
+fn local_apic_address() -> u64 {
+    match madt() {
+        Some(madt) => madt.local_apic_address,
+        None => unsafe { Msr::new(IA32_APIC_BASE).read() & 0xF_FFFF_F000 },
     }
 }
 
-pub fn enable_apic() {
-    disable_pic(); // Ensure PIC is disabled
-    info("Enabling APIC...");
+/// Reads the local APIC register at byte offset `register`.
+pub fn read_apic(register: isize) -> u32 {
+    unsafe { core::ptr::read_volatile(APIC_BASE.offset(register / 4)) }
+}
 
-    let spurious_reg = unsafe { APIC_BASE.offset(0xF0 / 4) };
-    let value = 0x100 | 0xFF; // enable + vector 255
-    unsafe { core::ptr::write_volatile(spurious_reg, value) };
+pub fn write_apic(register: isize, value: u32) {
+    unsafe { core::ptr::write_volatile(APIC_BASE.offset(register / 4), value) }
+}
 
-    // Set the timer to periodic mode
-    get_and_lock_renderer().println("Setting up APIC timer...");
-    unsafe {
-        let divide_reg = APIC_BASE.offset(0x3E0 / 4);
-        core::ptr::write_volatile(divide_reg, 0b0011); // divide by 16
+/// APIC ID of the CPU running this.
+pub fn local_apic_id() -> u32 {
+    read_apic(APIC_ID) >> 24
+}
+
+/// Signals the local APIC that the current interrupt has been handled.
+pub fn end_of_interrupt() {
+    write_apic(APIC_EOI, 0);
+}
+
+/// Sends an NMI to every other CPU, which halts them while a panic is in progress.
+pub fn stop_other_cpus() {
+    if unsafe { APIC_BASE }.is_null() {
+        return;
     }
+    write_apic(APIC_ICR_LOW, ICR_NMI_ALL_BUT_SELF);
+}
 
-    // Set the LVT timer to periodic mode
-    get_and_lock_renderer().println("Setting up LVT timer...");
-    unsafe {
-        let lvt_timer = APIC_BASE.offset(0x320 / 4);
-        core::ptr::write_volatile(lvt_timer, 0x20 | (1 << 17)); // vector 32 + periodic
+/// Routes the LINT pins the MADT wires to NMI on this CPU. Without a MADT, LINT1 is
+/// used as on a standard PC.
+pub fn register_nmi_sources() {
+    info!("Registering NMI sources...");
+    let write_lint = |lint: u8, value: u32| {
+        let register = if lint == 0 { LVT_LINT0 } else { LVT_LINT1 };
+        write_apic(register, value);
+        info!("LINT{} routed to NMI", lint);
+    };
+
+    let Some(madt) = madt() else {
+        write_lint(1, LVT_NMI);
+        return;
+    };
+
+    let apic_id = local_apic_id();
+    let uid = madt
+        .local_apics
+        .iter()
+        .find(|cpu| cpu.apic_id == apic_id)
+        .map(|cpu| cpu.processor_uid);
+    for nmi in madt
+        .local_apic_nmis
+        .iter()
+        .filter(|nmi| nmi.processor_uid.is_none() || nmi.processor_uid == uid)
+    {
+        // NMIs are always edge triggered, only the polarity can be chosen.
+        let mut value = LVT_NMI;
+        if nmi.polarity == Polarity::ActiveLow {
+            value |= LVT_ACTIVE_LOW;
+        }
+        write_lint(nmi.lint, value);
+    }
+}
+
+pub fn enable_apic() {
+    if madt().is_none_or(|madt| madt.pcat_compat) {
+        disable_pic(); // Ensure PIC is disabled
+    }
+    let apic_phys = local_apic_address();
+    info!("Enabling APIC at 0x{:x}...", apic_phys);
+    if let Some(madt) = madt() {
+        let cpus = madt.usable_cpus().count();
+        if cpus > 1 {
+            info!("{} CPUs found, only the boot CPU is used", cpus);
+        }
     }
 
-    // Set the initial count for the timer
-    get_and_lock_renderer().println("Setting up initial count...");
     unsafe {
-        let init_count = APIC_BASE.offset(0x380 / 4);
-        core::ptr::write_volatile(init_count, 10_000_000); // adjust to taste
+        APIC_BASE = map_mmio(PhysAddr::new(apic_phys), 0x1000, CacheType::Uncacheable).as_mut_ptr();
     }
 
+    let spurious_reg = unsafe { APIC_BASE.offset(0xF0 / 4) };
+    let value = 0x100 | 0xFF; // enable + vector 255
+    unsafe { core::ptr::write_volatile(spurious_reg, value) };
+
+    init_time();
+
     register_nmi_sources(); // Register NMI sources for ACPI
 }
 
 // Modify the timer interrupt handler to send EOI
 extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    tick();
     // PIC can eat it, get with the times and use APIC
 
     // As for APIC:
-    unsafe {
-        // EOI to APIC
-        let eoi_reg = APIC_BASE.offset(0xB0 / 4);
-        core::ptr::write_volatile(eoi_reg, 0);
-    }
+    end_of_interrupt();
     let _ = stack_frame;
 }
@@ -0,0 +1,79 @@
+use boyinfo::{BootInfo, MemoryRegionKind};
+use log::{error, info, warn};
+use spin::Once;
+
+pub static BOOT_INFO: Once<&'static BootInfo> = Once::new();
+
+/// Halts with a serial error if boyloader was built against a different boot info
+/// layout, since nothing else in the boot info can be trusted at that point.
+pub fn validate_boot_info(boot_info: &BootInfo) {
+    if let Err(err) = boot_info.validate() {
+        error!("Boot info rejected: {}", err);
+        error!("Rebuild boyloader and boykernel from the same tree.");
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+}
+
+/// Logs what the loader handed over and stores the boot info globally.
+pub fn init_boot_info(boot_info: &'static BootInfo) -> &'static BootInfo {
+    info!(
+        "Boot info v{}: {} memory regions, RSDP at 0x{:x}, SMBIOS at 0x{:x}",
+        boot_info.version,
+        boot_info.memory_map.len,
+        boot_info.rsdp_address,
+        boot_info.smbios_address
+    );
+    if boot_info.memory_map.dropped != 0 {
+        warn!(
+            "The memory map was cut short, {} firmware regions are missing and stay unused",
+            boot_info.memory_map.dropped
+        );
+    }
+    info!(
+        "Kernel loaded at 0x{:x}-0x{:x} (virtual 0x{:x}-0x{:x})",
+        boot_info.kernel.phys_start,
+        boot_info.kernel.phys_end,
+        boot_info.kernel.virt_start,
+        boot_info.kernel.virt_end
+    );
+    // Subtracting the slide from an address gives its address in the ELF file.
+    info!(
+        "Kernel slide: 0x{:x} ({})",
+        boot_info.kernel.slide,
+        if boot_info.kernel.randomized != 0 {
+            "KASLR"
+        } else {
+            "KASLR disabled"
+        }
+    );
+    for segment in boot_info.kernel.segments() {
+        info!(
+            "  segment 0x{:x}-0x{:x} {} at 0x{:x}",
+            segment.virt_start,
+            segment.virt_start + segment.size,
+            segment.flags,
+            segment.phys_start
+        );
+    }
+    info!("Command line: \"{}\"", boot_info.cmdline.as_str());
+
+    let pages_of = |kind: MemoryRegionKind| -> u64 {
+        boot_info
+            .memory_map
+            .regions()
+            .iter()
+            .filter(|region| region.kind == kind)
+            .map(|region| region.page_count)
+            .sum()
+    };
+    info!(
+        "Memory: {} KiB usable, {} KiB boot services, {} KiB ACPI reclaimable",
+        pages_of(MemoryRegionKind::Usable) * 4,
+        pages_of(MemoryRegionKind::BootServices) * 4,
+        pages_of(MemoryRegionKind::AcpiReclaimable) * 4
+    );
+
+    BOOT_INFO.call_once(|| boot_info)
+}
@@ -0,0 +1,55 @@
+use core::fmt::{self, Write};
+
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{RENDERER, gop_render::SimplifiedRenderer, serial::serial_write_str};
+
+/// Formats onto the framebuffer console at the cursor.
+pub struct ScreenWriter<'a>(pub &'a SimplifiedRenderer<'a>);
+
+impl Write for ScreenWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_text(s);
+        Ok(())
+    }
+}
+
+/// Formats to the serial port and, once there is a renderer, the screen.
+struct Console<'a> {
+    screen: Option<&'a SimplifiedRenderer<'a>>,
+}
+
+impl Write for Console<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        serial_write_str(s);
+        if let Some(renderer) = self.screen {
+            renderer.write_text(s);
+        }
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    // An interrupt handler printing while the renderer is locked would deadlock.
+    without_interrupts(|| {
+        let renderer = RENDERER.get().map(|renderer| renderer.lock());
+        let mut console = Console {
+            screen: renderer.as_deref(),
+        };
+        let _ = console.write_fmt(args);
+    });
+}
+
+/// Prints to serial and the screen, like `print!`.
+#[macro_export]
+macro_rules! kprint {
+    ($($arg:tt)*) => ($crate::console::_print(format_args!($($arg)*)));
+}
+
+/// Prints a line to serial and the screen, like `println!`.
+#[macro_export]
+macro_rules! kprintln {
+    () => ($crate::kprint!("\n"));
+    ($($arg:tt)*) => ($crate::console::_print(format_args!("{}\n", format_args!($($arg)*))));
+}
@@ -0,0 +1,444 @@
+use core::{
+    arch::global_asm,
+    fmt::{self, Write},
+    ops::Range,
+};
+
+use log::{info, warn};
+use spin::Mutex;
+use x86_64::{
+    VirtAddr,
+    instructions::{hlt, interrupts::disable},
+    registers::control::{Cr0, Cr2, Cr3, Cr4},
+    structures::idt::InterruptDescriptorTable,
+};
+
+use crate::{
+    RENDERER,
+    backtrace::print_backtrace,
+    gdt::{DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, NMI_IST_INDEX},
+    gop_render::CURSOR_STATE,
+    paging::is_stack_guard,
+    panic::is_panicking,
+    serial::error,
+};
+
+const DEBUG: u8 = 1;
+const NMI: u8 = 2;
+const BREAKPOINT: u8 = 3;
+const DOUBLE_FAULT: u8 = 8;
+const INVALID_TSS: u8 = 10;
+const SEGMENT_NOT_PRESENT: u8 = 11;
+const STACK_SEGMENT_FAULT: u8 = 12;
+const GENERAL_PROTECTION: u8 = 13;
+const PAGE_FAULT: u8 = 14;
+
+/// Name and mnemonic of every architectural exception vector.
+const EXCEPTIONS: [(&str, &str); 32] = [
+    ("Divide error", "#DE"),
+    ("Debug", "#DB"),
+    ("Non-maskable interrupt", "NMI"),
+    ("Breakpoint", "#BP"),
+    ("Overflow", "#OF"),
+    ("Bound range exceeded", "#BR"),
+    ("Invalid opcode", "#UD"),
+    ("Device not available", "#NM"),
+    ("Double fault", "#DF"),
+    ("Coprocessor segment overrun", "-"),
+    ("Invalid TSS", "#TS"),
+    ("Segment not present", "#NP"),
+    ("Stack-segment fault", "#SS"),
+    ("General protection fault", "#GP"),
+    ("Page fault", "#PF"),
+    ("Reserved", "-"),
+    ("x87 floating-point exception", "#MF"),
+    ("Alignment check", "#AC"),
+    ("Machine check", "#MC"),
+    ("SIMD floating-point exception", "#XM"),
+    ("Virtualization exception", "#VE"),
+    ("Control protection exception", "#CP"),
+    ("Reserved", "-"),
+    ("Reserved", "-"),
+    ("Reserved", "-"),
+    ("Reserved", "-"),
+    ("Reserved", "-"),
+    ("Reserved", "-"),
+    ("Hypervisor injection exception", "#HV"),
+    ("VMM communication exception", "#VC"),
+    ("Security exception", "#SX"),
+    ("Reserved", "-"),
+];
+
+/// Everything the exception entry stubs save, lowest address first.
+#[repr(C)]
+#[derive(Debug)]
+pub struct ExceptionFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// Zero for exceptions that do not push one.
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+// One 16-byte stub per vector pushes a zero error code where the CPU does not push
+// one, then the vector, so every exception reaches `exception_dispatch` with the
+// same frame layout. Returning from the dispatcher resumes at the saved RIP.
+global_asm!(
+    ".pushsection .text",
+    ".balign 16",
+    ".global exception_stubs",
+    "exception_stubs:",
+    ".irp vector, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31",
+    ".balign 16",
+    ".if \\vector != 8 && (\\vector < 10 || \\vector > 14) && \\vector != 17 && \\vector != 21 && \\vector != 29 && \\vector != 30",
+    "push 0",
+    ".endif",
+    "push \\vector",
+    "jmp exception_common",
+    ".endr",
+    ".global exception_common",
+    "exception_common:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "mov rbx, rsp",
+    "and rsp, -16",
+    "cld",
+    "call {dispatch}",
+    "mov rsp, rbx",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "add rsp, 16",
+    "iretq",
+    ".popsection",
+    dispatch = sym exception_dispatch,
+);
+
+unsafe extern "C" {
+    static exception_stubs: u8;
+}
+
+const STUB_SIZE: u64 = 16;
+
+/// Points every architectural exception entry of `idt` at its entry stub. Double
+/// faults, NMIs and machine checks run on their own IST stacks.
+pub fn install_exception_handlers(idt: &mut InterruptDescriptorTable) {
+    let stub = |vector: u64| VirtAddr::from_ptr(&raw const exception_stubs) + vector * STUB_SIZE;
+    unsafe {
+        idt.divide_error.set_handler_addr(stub(0));
+        idt.debug.set_handler_addr(stub(1));
+        idt.non_maskable_interrupt
+            .set_handler_addr(stub(2))
+            .set_stack_index(NMI_IST_INDEX);
+        idt.breakpoint.set_handler_addr(stub(3));
+        idt.overflow.set_handler_addr(stub(4));
+        idt.bound_range_exceeded.set_handler_addr(stub(5));
+        idt.invalid_opcode.set_handler_addr(stub(6));
+        idt.device_not_available.set_handler_addr(stub(7));
+        idt.double_fault
+            .set_handler_addr(stub(8))
+            .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss.set_handler_addr(stub(10));
+        idt.segment_not_present.set_handler_addr(stub(11));
+        idt.stack_segment_fault.set_handler_addr(stub(12));
+        idt.general_protection_fault.set_handler_addr(stub(13));
+        idt.page_fault.set_handler_addr(stub(14));
+        idt.x87_floating_point.set_handler_addr(stub(16));
+        idt.alignment_check.set_handler_addr(stub(17));
+        idt.machine_check
+            .set_handler_addr(stub(18))
+            .set_stack_index(MACHINE_CHECK_IST_INDEX);
+        idt.simd_floating_point.set_handler_addr(stub(19));
+        idt.virtualization.set_handler_addr(stub(20));
+        idt.cp_protection_exception.set_handler_addr(stub(21));
+        idt.hv_injection_exception.set_handler_addr(stub(28));
+        idt.vmm_communication_exception.set_handler_addr(stub(29));
+        idt.security_exception.set_handler_addr(stub(30));
+    }
+}
+
+/// Where execution continues when an instruction in `faulting` raises an exception.
+#[derive(Debug, Clone)]
+struct Fixup {
+    faulting: Range<u64>,
+    landing: u64,
+}
+
+const MAX_FIXUPS: usize = 32;
+
+static FIXUPS: Mutex<heapless::Vec<Fixup, MAX_FIXUPS>> = Mutex::new(heapless::Vec::new());
+
+/// Makes any exception raised by an instruction in `faulting` resume at `landing`
+/// instead of halting the kernel. Returns `false` if the fixup table is full.
+pub fn register_fixup(faulting: Range<u64>, landing: u64) -> bool {
+    FIXUPS.lock().push(Fixup { faulting, landing }).is_ok()
+}
+
+fn find_fixup(rip: u64) -> Option<u64> {
+    // The faulting code might hold the lock, which then means there is no fixup.
+    let fixups = FIXUPS.try_lock()?;
+    fixups
+        .iter()
+        .find(|fixup| fixup.faulting.contains(&rip))
+        .map(|fixup| fixup.landing)
+}
+
+// Reads a u64 from `rdi`, returning it in rax with rdx = 0, or rdx = 1 if the load
+// faulted. The load is covered by a fixup registered in `init_exceptions`.
+global_asm!(
+    ".pushsection .text",
+    ".global probe_read_u64",
+    "probe_read_u64:",
+    "xor edx, edx",
+    ".global probe_read_u64_load",
+    "probe_read_u64_load:",
+    "mov rax, [rdi]",
+    ".global probe_read_u64_load_end",
+    "probe_read_u64_load_end:",
+    "ret",
+    ".global probe_read_u64_fault",
+    "probe_read_u64_fault:",
+    "xor eax, eax",
+    "mov edx, 1",
+    "ret",
+    ".popsection",
+);
+
+#[repr(C)]
+struct ProbeResult {
+    value: u64,
+    faulted: u64,
+}
+
+unsafe extern "sysv64" {
+    fn probe_read_u64(addr: u64) -> ProbeResult;
+    static probe_read_u64_load: u8;
+    static probe_read_u64_load_end: u8;
+    static probe_read_u64_fault: u8;
+}
+
+/// Registers the fixups the kernel itself relies on.
+pub fn init_exceptions() {
+    let address = |symbol: *const u8| symbol as u64;
+    let load = address(&raw const probe_read_u64_load)..address(&raw const probe_read_u64_load_end);
+    let landing = address(&raw const probe_read_u64_fault);
+    register_fixup(load, landing);
+}
+
+/// Reads a u64 at `addr`, or returns `None` instead of faulting if it is not mapped.
+pub fn read_u64_checked(addr: VirtAddr) -> Option<u64> {
+    if !addr.is_aligned(8u64) {
+        return None;
+    }
+    let result = unsafe { probe_read_u64(addr.as_u64()) };
+    (result.faulted == 0).then_some(result.value)
+}
+
+/// Prints a line on serial and, unless someone holds the renderer or the cursor, on
+/// screen too.
+///
+/// Nothing here touches the heap or waits for a lock, since the code that faulted
+/// may hold either.
+pub fn fatal_println(args: fmt::Arguments) {
+    let mut line: heapless::String<256> = heapless::String::new();
+    let _ = line.write_fmt(args);
+    error(&line);
+    if CURSOR_STATE.is_locked() {
+        return;
+    }
+    if let Some(renderer) = RENDERER.get().and_then(|renderer| renderer.try_lock()) {
+        renderer.print(&line);
+    }
+}
+
+/// Stops this CPU for good.
+pub fn halt() -> ! {
+    loop {
+        disable();
+        hlt();
+    }
+}
+
+fn describe_error_code(vector: u8, error_code: u64) {
+    match vector {
+        PAGE_FAULT => {
+            let cause = if error_code & 1 != 0 {
+                "protection violation"
+            } else {
+                "page not present"
+            };
+            let access = if error_code & (1 << 4) != 0 {
+                "instruction fetch"
+            } else if error_code & (1 << 1) != 0 {
+                "write"
+            } else {
+                "read"
+            };
+            let mode = if error_code & (1 << 2) != 0 {
+                "user"
+            } else {
+                "kernel"
+            };
+            fatal_println(format_args!(
+                "Page fault: {} {} at {:#x} ({}){}{}",
+                mode,
+                access,
+                Cr2::read_raw(),
+                cause,
+                if error_code & (1 << 3) != 0 {
+                    ", reserved bit set"
+                } else {
+                    ""
+                },
+                if error_code & (1 << 5) != 0 {
+                    ", protection key"
+                } else {
+                    ""
+                }
+            ));
+        }
+        INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION
+            if error_code != 0 =>
+        {
+            let table = match (error_code >> 1) & 0b11 {
+                0 => "GDT",
+                2 => "LDT",
+                _ => "IDT",
+            };
+            fatal_println(format_args!(
+                "Selector: {} index {}{}",
+                table,
+                (error_code >> 3) & 0x1FFF,
+                if error_code & 1 != 0 {
+                    ", external event"
+                } else {
+                    ""
+                }
+            ));
+        }
+        _ => {}
+    }
+}
+
+fn dump_registers(frame: &ExceptionFrame) {
+    fatal_println(format_args!(
+        "RIP {:#018x} CS  {:#06x} RFLAGS {:#010x}",
+        frame.rip, frame.cs, frame.rflags
+    ));
+    fatal_println(format_args!(
+        "RSP {:#018x} SS  {:#06x} error code {:#x}",
+        frame.rsp, frame.ss, frame.error_code
+    ));
+    fatal_println(format_args!(
+        "RAX {:#018x} RBX {:#018x} RCX {:#018x}",
+        frame.rax, frame.rbx, frame.rcx
+    ));
+    fatal_println(format_args!(
+        "RDX {:#018x} RSI {:#018x} RDI {:#018x}",
+        frame.rdx, frame.rsi, frame.rdi
+    ));
+    fatal_println(format_args!(
+        "RBP {:#018x} R8  {:#018x} R9  {:#018x}",
+        frame.rbp, frame.r8, frame.r9
+    ));
+    fatal_println(format_args!(
+        "R10 {:#018x} R11 {:#018x} R12 {:#018x}",
+        frame.r10, frame.r11, frame.r12
+    ));
+    fatal_println(format_args!(
+        "R13 {:#018x} R14 {:#018x} R15 {:#018x}",
+        frame.r13, frame.r14, frame.r15
+    ));
+    fatal_println(format_args!(
+        "CR0 {:#010x} CR2 {:#018x} CR3 {:#018x} CR4 {:#010x}",
+        Cr0::read_raw(),
+        Cr2::read_raw(),
+        Cr3::read().0.start_address().as_u64(),
+        Cr4::read_raw()
+    ));
+}
+
+extern "sysv64" fn exception_dispatch(frame: &mut ExceptionFrame) {
+    let vector = frame.vector as u8;
+    match vector {
+        // Traps that are only reported, execution simply continues.
+        DEBUG | BREAKPOINT => {
+            info!("{} at {:#x}", EXCEPTIONS[vector as usize].0, frame.rip);
+            return;
+        }
+        // Another CPU panicked and wants everyone else to stop.
+        NMI if is_panicking() => halt(),
+        NMI => {
+            warn!("Non-maskable interrupt at {:#x}", frame.rip);
+            return;
+        }
+        _ => {}
+    }
+
+    if let Some(landing) = find_fixup(frame.rip) {
+        frame.rip = landing;
+        return;
+    }
+
+    let (name, mnemonic) = EXCEPTIONS[vector as usize];
+    fatal_println(format_args!(
+        "EXCEPTION: {} ({}, vector {})",
+        name, mnemonic, vector
+    ));
+    describe_error_code(vector, frame.error_code);
+    dump_registers(frame);
+    if vector == DOUBLE_FAULT
+        && (is_stack_guard(VirtAddr::new_truncate(Cr2::read_raw()))
+            || is_stack_guard(VirtAddr::new_truncate(frame.rsp)))
+    {
+        fatal_println(format_args!(
+            "The kernel stack overflowed into its guard page"
+        ));
+    }
+    print_backtrace(frame.rip, frame.rbp);
+    halt()
+}
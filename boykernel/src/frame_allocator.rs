@@ -0,0 +1,275 @@
+use boyinfo::{BootInfo, MemoryRegionKind};
+use log::info;
+use spin::{Mutex, Once};
+use x86_64::{
+    PhysAddr,
+    structures::paging::{FrameAllocator, PhysFrame, Size4KiB},
+};
+
+pub const FRAME_SIZE: u64 = 4096;
+
+/// Frames below 1 MiB are never handed out, they are kept for real-mode
+/// trampolines and whatever legacy data the firmware left there.
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
+/// Bitmap allocator for 4 KiB physical frames, one bit per frame (set = in use).
+///
+/// The bitmap covers every frame up to the end of the highest region that can ever
+/// become usable and lives in the first usable region big enough to hold it.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    /// Number of frames the bitmap covers, starting at physical address 0.
+    frame_count: usize,
+    /// Frames handed to the allocator, whether currently free or not.
+    total_frames: usize,
+    free_frames: usize,
+    /// Where the next single-frame search starts.
+    next_hint: usize,
+}
+
+#[allow(dead_code)]
+impl BitmapFrameAllocator {
+    /// Builds the allocator from the boot info memory map.
+    ///
+    /// Only [`MemoryRegionKind::Usable`] memory is handed out at first; boot services
+    /// and ACPI memory can be added later through [`BitmapFrameAllocator::reclaim`].
+    pub fn new(boot_info: &BootInfo) -> Self {
+        let regions = boot_info.memory_map.regions();
+        let max_address = regions
+            .iter()
+            .filter(|region| is_reclaimable(region.kind))
+            .map(|region| region.phys_end())
+            .max()
+            .unwrap_or(0);
+
+        let frame_count = (max_address / FRAME_SIZE) as usize;
+        let bitmap_words = frame_count.div_ceil(64);
+        let bitmap_bytes = (bitmap_words * 8) as u64;
+        let bitmap_pages = bitmap_bytes.div_ceil(FRAME_SIZE);
+
+        let bitmap_start = regions
+            .iter()
+            .filter(|region| region.kind == MemoryRegionKind::Usable)
+            .map(|region| (region.phys_start.max(LOW_MEMORY_END), region.phys_end()))
+            .find(|(start, end)| end.saturating_sub(*start) >= bitmap_pages * FRAME_SIZE)
+            .map(|(start, _)| start)
+            .expect("No usable memory region is large enough for the frame bitmap");
+
+        // Still on the firmware identity map here, see `BitmapFrameAllocator::rebase`.
+        let bitmap =
+            unsafe { core::slice::from_raw_parts_mut(bitmap_start as *mut u64, bitmap_words) };
+        bitmap.fill(u64::MAX);
+
+        let mut allocator = Self {
+            bitmap,
+            frame_count,
+            total_frames: 0,
+            free_frames: 0,
+            next_hint: 0,
+        };
+
+        allocator.reclaim(boot_info, MemoryRegionKind::Usable);
+
+        let framebuffer = &boot_info.framebuffer;
+        allocator.reserve_range(bitmap_start, bitmap_start + bitmap_pages * FRAME_SIZE);
+        allocator.reserve_range(boot_info.kernel.phys_start, boot_info.kernel.phys_end);
+        allocator.reserve_range(
+            framebuffer.address,
+            framebuffer.address + framebuffer.size as u64,
+        );
+
+        allocator
+    }
+
+    /// Moves the bitmap to where it is reachable through a physical memory offset mapping.
+    ///
+    /// # Safety
+    ///
+    /// `offset` must map the frames backing the bitmap.
+    pub unsafe fn rebase(&mut self, offset: u64) {
+        let bitmap = self.bitmap.as_mut_ptr() as u64 + offset;
+        self.bitmap =
+            unsafe { core::slice::from_raw_parts_mut(bitmap as *mut u64, self.bitmap.len()) };
+    }
+
+    /// Hands every region of `kind` to the allocator.
+    ///
+    /// Must only be called for [`MemoryRegionKind::BootServices`] once the kernel no
+    /// longer runs on the firmware page tables and stack, and for
+    /// [`MemoryRegionKind::AcpiReclaimable`] once the ACPI tables have been parsed.
+    pub fn reclaim(&mut self, boot_info: &BootInfo, kind: MemoryRegionKind) {
+        for region in boot_info
+            .memory_map
+            .regions()
+            .iter()
+            .filter(|r| r.kind == kind)
+        {
+            let start = region.phys_start.max(LOW_MEMORY_END).div_ceil(FRAME_SIZE) as usize;
+            let end = ((region.phys_end() / FRAME_SIZE) as usize).min(self.frame_count);
+            for frame in start..end {
+                if self.is_used(frame) {
+                    self.set_free(frame);
+                    self.total_frames += 1;
+                }
+            }
+        }
+    }
+
+    /// Marks every frame touching `start..end` as in use for good.
+    fn reserve_range(&mut self, start: u64, end: u64) {
+        let first = (start / FRAME_SIZE) as usize;
+        let last = (end.div_ceil(FRAME_SIZE) as usize).min(self.frame_count);
+        for frame in first..last {
+            if !self.is_used(frame) {
+                self.set_used(frame);
+                self.total_frames -= 1;
+            }
+        }
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / 64] & (1 << (frame % 64)) != 0
+    }
+
+    fn set_used(&mut self, frame: usize) {
+        self.bitmap[frame / 64] |= 1 << (frame % 64);
+        self.free_frames -= 1;
+    }
+
+    fn set_free(&mut self, frame: usize) {
+        self.bitmap[frame / 64] &= !(1 << (frame % 64));
+        self.free_frames += 1;
+    }
+
+    /// Allocates a single 4 KiB frame.
+    pub fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let words = self.bitmap.len();
+        let start_word = self.next_hint / 64;
+
+        for offset in 0..words {
+            let word = (start_word + offset) % words;
+            if self.bitmap[word] == u64::MAX {
+                continue;
+            }
+            let frame = word * 64 + (!self.bitmap[word]).trailing_zeros() as usize;
+            if frame >= self.frame_count {
+                continue;
+            }
+            self.set_used(frame);
+            self.next_hint = frame + 1;
+            return Some(frame_at(frame));
+        }
+        None
+    }
+
+    /// Allocates `count` physically contiguous frames and returns the first one.
+    pub fn allocate_frames(&mut self, count: usize) -> Option<PhysFrame> {
+        if count == 0 {
+            return None;
+        }
+        if count == 1 {
+            return self.allocate_frame();
+        }
+
+        let mut run_start = 0;
+        let mut run_len = 0;
+        for frame in 0..self.frame_count {
+            if self.is_used(frame) {
+                run_len = 0;
+                continue;
+            }
+            if run_len == 0 {
+                run_start = frame;
+            }
+            run_len += 1;
+            if run_len == count {
+                for frame in run_start..run_start + count {
+                    self.set_used(frame);
+                }
+                return Some(frame_at(run_start));
+            }
+        }
+        None
+    }
+
+    /// Returns a frame obtained from [`BitmapFrameAllocator::allocate_frame`].
+    pub fn free_frame(&mut self, frame: PhysFrame) {
+        self.free_frames_from(frame, 1);
+    }
+
+    /// Returns `count` frames starting at `frame`, as obtained from
+    /// [`BitmapFrameAllocator::allocate_frames`].
+    pub fn free_frames_from(&mut self, frame: PhysFrame, count: usize) {
+        let first = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        for index in first..first + count {
+            assert!(
+                index < self.frame_count && self.is_used(index),
+                "Double free of physical frame 0x{:x}",
+                index as u64 * FRAME_SIZE
+            );
+            self.set_free(index);
+        }
+        self.next_hint = self.next_hint.min(first);
+    }
+
+    /// Total memory managed by the allocator, in bytes.
+    pub fn total_memory(&self) -> u64 {
+        self.total_frames as u64 * FRAME_SIZE
+    }
+
+    /// Memory currently free, in bytes.
+    pub fn free_memory(&self) -> u64 {
+        self.free_frames as u64 * FRAME_SIZE
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        BitmapFrameAllocator::allocate_frame(self)
+    }
+}
+
+fn frame_at(index: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+}
+
+/// Kinds of memory the allocator may manage at some point during boot.
+fn is_reclaimable(kind: MemoryRegionKind) -> bool {
+    matches!(
+        kind,
+        MemoryRegionKind::Usable
+            | MemoryRegionKind::BootServices
+            | MemoryRegionKind::AcpiReclaimable
+    )
+}
+
+pub static FRAME_ALLOCATOR: Once<Mutex<BitmapFrameAllocator>> = Once::new();
+
+/// Builds the global frame allocator from the boot info memory map.
+///
+/// Runs before the heap is usable, so statistics are printed separately.
+pub fn init_frame_allocator(boot_info: &BootInfo) {
+    FRAME_ALLOCATOR.call_once(|| Mutex::new(BitmapFrameAllocator::new(boot_info)));
+}
+
+/// Helper function to get and lock the global frame allocator
+pub fn get_and_lock_frame_allocator() -> spin::MutexGuard<'static, BitmapFrameAllocator> {
+    FRAME_ALLOCATOR
+        .get()
+        .expect("Frame allocator is not initialized")
+        .lock()
+}
+
+/// Reports total and free physical memory over serial.
+pub fn print_memory_stats() {
+    // Don't hold the lock while formatting, the heap may need frames to grow.
+    let (total, free) = {
+        let allocator = get_and_lock_frame_allocator();
+        (allocator.total_memory(), allocator.free_memory())
+    };
+    info!(
+        "Physical memory: {} KiB total, {} KiB free",
+        total / 1024,
+        free / 1024
+    );
+}
@@ -1,9 +1,43 @@
-#[repr(C)]
-pub struct FramebufferInfo {
-    pub address: u64,
-    pub size: usize,
-    pub width: usize,
-    pub height: usize,
-    pub stride: usize,
-    pub format: u32,
+pub use boyinfo::{FramebufferInfo, PixelFormat};
+use spin::Once;
+use x86_64::PhysAddr;
+
+use crate::paging::{CacheType, map_mmio};
+
+static FRAMEBUFFER: Once<FramebufferInfo> = Once::new();
+
+/// Maps the framebuffer write-combining and returns its info pointing at the mapping.
+pub fn map_framebuffer(info: &FramebufferInfo) -> &'static FramebufferInfo {
+    FRAMEBUFFER.call_once(|| {
+        let address = map_mmio(
+            PhysAddr::new(info.address),
+            info.size,
+            CacheType::WriteCombining,
+        );
+        FramebufferInfo {
+            address: address.as_u64(),
+            ..*info
+        }
+    })
+}
+
+/// Converts a `0xRRGGBB` color into a pixel of the given format.
+///
+/// Each 8-bit channel is scaled to the width of its mask, so this also covers
+/// formats with fewer or more than 8 bits per channel.
+pub fn pack_color(format: &PixelFormat, rgb: u32) -> u32 {
+    let Some(masks) = format.masks() else {
+        return 0;
+    };
+    let channel = |value: u32, mask: u32| -> u32 {
+        if mask == 0 {
+            return 0;
+        }
+        let max = (1u64 << mask.count_ones()) - 1;
+        let scaled = (value as u64 * max / 0xFF) as u32;
+        (scaled << mask.trailing_zeros()) & mask
+    };
+    channel((rgb >> 16) & 0xFF, masks.red)
+        | channel((rgb >> 8) & 0xFF, masks.green)
+        | channel(rgb & 0xFF, masks.blue)
 }
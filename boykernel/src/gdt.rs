@@ -0,0 +1,67 @@
+use lazy_static::lazy_static;
+use x86_64::{
+    VirtAddr,
+    instructions::{
+        segmentation::{CS, DS, ES, SS, Segment},
+        tables::load_tss,
+    },
+    structures::{
+        gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
+        tss::TaskStateSegment,
+    },
+};
+
+/// IST slots used by the IDT, each with its own stack so these exceptions work
+/// even when the current stack is unusable.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+const IST_STACK_COUNT: usize = 3;
+const IST_STACK_SIZE: usize = 5 * 4096;
+
+#[repr(C, align(16))]
+struct IstStack([u8; IST_STACK_SIZE]);
+
+static mut IST_STACKS: [IstStack; IST_STACK_COUNT] =
+    [const { IstStack([0; IST_STACK_SIZE]) }; IST_STACK_COUNT];
+
+struct Selectors {
+    code: SegmentSelector,
+    data: SegmentSelector,
+    tss: SegmentSelector,
+}
+
+lazy_static! {
+    static ref TSS: TaskStateSegment = {
+        let mut tss = TaskStateSegment::new();
+        let stacks = VirtAddr::from_ptr(&raw const IST_STACKS);
+        for index in 0..IST_STACK_COUNT {
+            // Stacks grow down, so each IST entry points at the end of its stack.
+            tss.interrupt_stack_table[index] = stacks + ((index + 1) * IST_STACK_SIZE) as u64;
+        }
+        tss
+    };
+    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+        let mut gdt = GlobalDescriptorTable::new();
+        let code = gdt.append(Descriptor::kernel_code_segment());
+        let data = gdt.append(Descriptor::kernel_data_segment());
+        let tss = gdt.append(Descriptor::tss_segment(&TSS));
+        (gdt, Selectors { code, data, tss })
+    };
+}
+
+/// Loads the kernel GDT and TSS and reloads every segment register.
+///
+/// Replaces the firmware GDT, which sits in boot services memory that is about to be
+/// handed to the frame allocator.
+pub fn init_gdt() {
+    let (gdt, selectors) = &*GDT;
+    gdt.load();
+    unsafe {
+        CS::set_reg(selectors.code);
+        DS::set_reg(selectors.data);
+        ES::set_reg(selectors.data);
+        SS::set_reg(selectors.data);
+        load_tss(selectors.tss);
+    }
+}
@@ -1,14 +1,18 @@
 use core::slice::from_raw_parts_mut;
 use spin::Mutex;
 
-use crate::{font::PSF2Font, framebuffer::FramebufferInfo, strings::concat, watermark::parse_ppm};
+use crate::{
+    font::PSF2Font,
+    framebuffer::{FramebufferInfo, pack_color},
+    watermark::parse_ppm,
+};
 
 /// Graphics abstraction for the buffer graphics
 pub struct SimplifiedRenderer<'a> {
     buffer: &'a FramebufferInfo,
 }
 
-/// Enum representing colors
+/// Enum representing colors as `0xRRGGBB`
 pub enum Color {
     Black = 0x000000,
     White = 0xFFFFFF,
@@ -45,10 +49,15 @@ impl<'a> SimplifiedRenderer<'a> {
         Self { buffer }
     }
 
+    /// Converts `color` into the framebuffer's pixel format.
+    fn pack(&self, color: Color) -> u32 {
+        pack_color(&self.buffer.format, color.as_u32())
+    }
+
     pub fn clear_screen(&self) {
         unsafe {
             for i in 0..(self.buffer.size / 4) {
-                *(self.buffer.address as *mut u32).add(i) = Color::Black.as_u32();
+                *(self.buffer.address as *mut u32).add(i) = self.pack(Color::Black);
             }
         }
     }
@@ -65,7 +74,7 @@ impl<'a> SimplifiedRenderer<'a> {
             50,
             100,
             50,
-            Color::Red.as_u32(),
+            self.pack(Color::Red),
         );
         draw_rectangle(
             self.buffer.address as *mut u32,
@@ -75,7 +84,7 @@ impl<'a> SimplifiedRenderer<'a> {
             100,
             80,
             120,
-            Color::Green.as_u32(),
+            self.pack(Color::Green),
         );
         draw_rectangle(
             self.buffer.address as *mut u32,
@@ -85,7 +94,7 @@ impl<'a> SimplifiedRenderer<'a> {
             200,
             60,
             60,
-            Color::Blue.as_u32(),
+            self.pack(Color::Blue),
         );
 
         draw_rectangle(
@@ -96,7 +105,7 @@ impl<'a> SimplifiedRenderer<'a> {
             0,
             20,
             20,
-            Color::Red.as_u32(),
+            self.pack(Color::Red),
         );
         draw_rectangle(
             self.buffer.address as *mut u32,
@@ -106,7 +115,7 @@ impl<'a> SimplifiedRenderer<'a> {
             0,
             20,
             20,
-            Color::Red.as_u32(),
+            self.pack(Color::Red),
         );
         draw_rectangle(
             self.buffer.address as *mut u32,
@@ -116,7 +125,7 @@ impl<'a> SimplifiedRenderer<'a> {
             self.buffer.height - 20,
             20,
             20,
-            Color::Red.as_u32(),
+            self.pack(Color::Red),
         );
         draw_rectangle(
             self.buffer.address as *mut u32,
@@ -126,7 +135,7 @@ impl<'a> SimplifiedRenderer<'a> {
             self.buffer.height - 20,
             20,
             20,
-            Color::Red.as_u32(),
+            self.pack(Color::Red),
         );
 
         draw_border(
@@ -134,7 +143,7 @@ impl<'a> SimplifiedRenderer<'a> {
             width,
             stride,
             self.buffer.height,
-            Color::Yellow.as_u32(),
+            self.pack(Color::Yellow),
         );
     }
 
@@ -155,8 +164,8 @@ impl<'a> SimplifiedRenderer<'a> {
                 self.buffer.width,
                 10 + (i * letter_width),
                 10,
-                Color::White.as_u32(),
-                Color::Black.as_u32(),
+                self.pack(Color::White),
+                self.pack(Color::Black),
                 &font,
                 ch,
             );
@@ -167,8 +176,8 @@ impl<'a> SimplifiedRenderer<'a> {
                 self.buffer.width,
                 10 + (i * letter_width),
                 10 + (font.header.height as usize + PADDING),
-                Color::White.as_u32(),
-                Color::Black.as_u32(),
+                self.pack(Color::White),
+                self.pack(Color::Black),
                 &font,
                 ch,
             );
@@ -181,10 +190,25 @@ impl<'a> SimplifiedRenderer<'a> {
     }
 
     pub fn println(&self, text: &str) {
-        self.print(&concat(text, "\n"));
+        self.write_text(text);
+        self.new_line();
     }
 
+    /// Like [`println`](Self::println), the line always ends.
     pub fn print(&self, text: &str) {
+        self.write_text(text);
+        self.new_line();
+    }
+
+    fn new_line(&self) {
+        let font = crate::font::load_font().unwrap();
+        let mut cursor = CURSOR_STATE.lock();
+        cursor.x = 10; // Reset to the start of the line with padding
+        cursor.y += font.header.height as usize;
+    }
+
+    /// Draws `text` at the cursor and leaves the cursor right after it.
+    pub fn write_text(&self, text: &str) {
         let font = crate::font::load_font().unwrap();
         let letter_width = font.header.width as usize;
 
@@ -222,7 +246,7 @@ impl<'a> SimplifiedRenderer<'a> {
                     let start_index = row * self.buffer.width;
                     let end_index = start_index + self.buffer.width;
                     for pixel in &mut buffer_slice[start_index..end_index] {
-                        *pixel = Color::Black.as_u32();
+                        *pixel = self.pack(Color::Black);
                     }
                 }
             }
@@ -232,8 +256,8 @@ impl<'a> SimplifiedRenderer<'a> {
                 self.buffer.width,
                 cursor.x,
                 cursor.y,
-                Color::White.as_u32(),
-                Color::Black.as_u32(),
+                self.pack(Color::White),
+                self.pack(Color::Black),
                 &font,
                 ch as u8,
             );
@@ -245,10 +269,6 @@ impl<'a> SimplifiedRenderer<'a> {
                 cursor.y += font.header.height as usize;
             }
         }
-
-        // Since we're println and not print, always go down.
-        cursor.x = 10; // Reset to the start of the line with padding
-        cursor.y += font.header.height as usize;
     }
 
     pub fn show_watermark(&self) {
@@ -275,7 +295,7 @@ impl<'a> SimplifiedRenderer<'a> {
                         let r = pixel_data[pixel_index] as u32;
                         let g = pixel_data[pixel_index + 1] as u32;
                         let b = pixel_data[pixel_index + 2] as u32;
-                        let color = (0xFF << 24) | (r << 16) | (g << 8) | b;
+                        let color = pack_color(&self.buffer.format, (r << 16) | (g << 8) | b);
                         buffer_slice[index] = color;
                     }
                 }
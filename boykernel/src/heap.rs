@@ -0,0 +1,316 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem::size_of,
+    ptr::null_mut,
+};
+
+use log::info;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    frame_allocator::{FRAME_ALLOCATOR, FRAME_SIZE},
+    paging::phys_to_virt,
+};
+
+/// Static memory used before the frame allocator exists (boot info checks and such).
+const BOOTSTRAP_HEAP_SIZE: usize = 16 * 1024;
+/// Pages taken from the frame allocator by [`init_heap`].
+const INITIAL_HEAP_PAGES: usize = 64;
+/// Minimum number of pages requested every time the heap runs dry.
+const HEAP_GROWTH_PAGES: usize = 16;
+/// Every block is a multiple of this, so leftovers are always big enough for a `FreeBlock`.
+const BLOCK_ALIGN: usize = 16;
+const MIN_BLOCK_SIZE: usize = size_of::<FreeBlock>();
+
+#[repr(C, align(16))]
+struct BootstrapArena([u8; BOOTSTRAP_HEAP_SIZE]);
+
+static mut BOOTSTRAP_ARENA: BootstrapArena = BootstrapArena([0; BOOTSTRAP_HEAP_SIZE]);
+
+/// Header written at the start of every free block.
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+/// Usage statistics of the kernel heap, in bytes unless stated otherwise.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub total: usize,
+    pub used: usize,
+    pub free_blocks: usize,
+    pub largest_free_block: usize,
+    pub allocations: usize,
+}
+
+/// First-fit allocator over an address-ordered free list.
+///
+/// Keeping the list sorted means a freed block only has to look at its two
+/// neighbours to coalesce with them.
+struct Heap {
+    head: *mut FreeBlock,
+    bootstrapped: bool,
+    total: usize,
+    used: usize,
+    allocations: usize,
+}
+
+// The free list is only ever touched behind the `Mutex` in `KernelHeap`.
+unsafe impl Send for Heap {}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+fn block_size(layout: Layout) -> usize {
+    align_up(layout.size().max(MIN_BLOCK_SIZE), BLOCK_ALIGN)
+}
+
+impl Heap {
+    const fn new() -> Self {
+        Self {
+            head: null_mut(),
+            bootstrapped: false,
+            total: 0,
+            used: 0,
+            allocations: 0,
+        }
+    }
+
+    fn ensure_bootstrapped(&mut self) {
+        if !self.bootstrapped {
+            self.bootstrapped = true;
+            let arena = &raw mut BOOTSTRAP_ARENA;
+            unsafe { self.add_region(arena as usize, BOOTSTRAP_HEAP_SIZE) };
+        }
+    }
+
+    /// Adds fresh memory to the heap.
+    ///
+    /// # Safety
+    ///
+    /// `start..start + size` must be mapped, writable and unused by anything else.
+    unsafe fn add_region(&mut self, start: usize, size: usize) {
+        let aligned_start = align_up(start, BLOCK_ALIGN);
+        let size = (size - (aligned_start - start)) & !(BLOCK_ALIGN - 1);
+        if size < MIN_BLOCK_SIZE {
+            return;
+        }
+        self.total += size;
+        unsafe { self.insert_free(aligned_start, size) };
+    }
+
+    /// Puts `addr..addr + size` back on the free list, merging it with its neighbours.
+    /// Returns false if the block overlaps memory that is already free.
+    unsafe fn insert_free(&mut self, addr: usize, size: usize) -> bool {
+        unsafe {
+            let mut prev: *mut FreeBlock = null_mut();
+            let mut next = self.head;
+            while !next.is_null() && (next as usize) < addr {
+                prev = next;
+                next = (*next).next;
+            }
+
+            let overlaps_prev = !prev.is_null() && prev as usize + (*prev).size > addr;
+            let overlaps_next = !next.is_null() && addr + size > next as usize;
+            if overlaps_prev || overlaps_next {
+                return false;
+            }
+
+            let block = addr as *mut FreeBlock;
+            block.write(FreeBlock { size, next });
+            if prev.is_null() {
+                self.head = block;
+            } else {
+                (*prev).next = block;
+            }
+
+            if !next.is_null() && addr + size == next as usize {
+                (*block).size += (*next).size;
+                (*block).next = (*next).next;
+            }
+            if !prev.is_null() && prev as usize + (*prev).size == addr {
+                (*prev).size += (*block).size;
+                (*prev).next = (*block).next;
+            }
+            true
+        }
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let size = block_size(layout);
+        let align = layout.align().max(BLOCK_ALIGN);
+
+        unsafe {
+            let mut prev: *mut FreeBlock = null_mut();
+            let mut current = self.head;
+            while !current.is_null() {
+                let block_start = current as usize;
+                let block_end = block_start + (*current).size;
+                let next = (*current).next;
+
+                // Padding in front of the allocation must be able to hold a free block.
+                let mut alloc_start = align_up(block_start, align);
+                if alloc_start != block_start && alloc_start - block_start < MIN_BLOCK_SIZE {
+                    alloc_start = align_up(block_start + MIN_BLOCK_SIZE, align);
+                }
+
+                if let Some(alloc_end) = alloc_start.checked_add(size)
+                    && alloc_end <= block_end
+                {
+                    if prev.is_null() {
+                        self.head = next;
+                    } else {
+                        (*prev).next = next;
+                    }
+
+                    if alloc_start > block_start {
+                        self.insert_free(block_start, alloc_start - block_start);
+                    }
+                    if block_end > alloc_end {
+                        self.insert_free(alloc_end, block_end - alloc_end);
+                    }
+
+                    self.used += size;
+                    self.allocations += 1;
+                    return alloc_start as *mut u8;
+                }
+
+                prev = current;
+                current = next;
+            }
+        }
+
+        null_mut()
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) -> bool {
+        let size = block_size(layout);
+        if !unsafe { self.insert_free(ptr as usize, size) } {
+            return false;
+        }
+        self.used -= size;
+        self.allocations -= 1;
+        true
+    }
+
+    /// Takes more frames from the frame allocator so that `layout` fits.
+    fn grow_for(&mut self, layout: Layout) -> bool {
+        let needed = block_size(layout) + layout.align() + MIN_BLOCK_SIZE;
+        self.grow(needed.div_ceil(FRAME_SIZE as usize).max(HEAP_GROWTH_PAGES))
+    }
+
+    /// Adds `pages` physically contiguous frames to the heap.
+    fn grow(&mut self, pages: usize) -> bool {
+        let Some(frame_allocator) = FRAME_ALLOCATOR.get() else {
+            return false;
+        };
+        let Some(frame) = frame_allocator.lock().allocate_frames(pages) else {
+            return false;
+        };
+
+        let start = phys_to_virt(frame.start_address()).as_u64() as usize;
+        unsafe { self.add_region(start, pages * FRAME_SIZE as usize) };
+        true
+    }
+
+    fn stats(&self) -> HeapStats {
+        let mut free_blocks = 0;
+        let mut largest_free_block = 0;
+        let mut current = self.head;
+        while !current.is_null() {
+            unsafe {
+                free_blocks += 1;
+                largest_free_block = largest_free_block.max((*current).size);
+                current = (*current).next;
+            }
+        }
+
+        HeapStats {
+            total: self.total,
+            used: self.used,
+            free_blocks,
+            largest_free_block,
+            allocations: self.allocations,
+        }
+    }
+}
+
+/// The kernel's global allocator.
+///
+/// Starts out on a small static arena and grows with frames from the physical
+/// frame allocator whenever an allocation does not fit.
+pub struct KernelHeap {
+    inner: Mutex<Heap>,
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (ptr, stats) = without_interrupts(|| {
+            let mut heap = self.inner.lock();
+            heap.ensure_bootstrapped();
+            loop {
+                let ptr = heap.allocate(layout);
+                if !ptr.is_null() || !heap.grow_for(layout) {
+                    break (ptr, heap.stats());
+                }
+            }
+        });
+
+        // The lock is released by now, so the panic handler is free to allocate.
+        if ptr.is_null() {
+            panic!(
+                "Kernel heap exhausted: {} bytes (align {}) requested, {} of {} bytes used, largest free block {} bytes",
+                layout.size(),
+                layout.align(),
+                stats.used,
+                stats.total,
+                stats.largest_free_block
+            );
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let freed = without_interrupts(|| unsafe { self.inner.lock().deallocate(ptr, layout) });
+        if !freed {
+            panic!(
+                "Kernel heap corruption: double free of {} bytes at {:p}",
+                layout.size(),
+                ptr
+            );
+        }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: KernelHeap = KernelHeap {
+    inner: Mutex::new(Heap::new()),
+};
+
+/// Moves the heap onto frames from the frame allocator.
+pub fn init_heap() {
+    let grown = without_interrupts(|| GLOBAL.inner.lock().grow(INITIAL_HEAP_PAGES));
+    if !grown {
+        panic!("Not enough physical memory for the kernel heap");
+    }
+    print_heap_stats();
+}
+
+pub fn heap_stats() -> HeapStats {
+    without_interrupts(|| GLOBAL.inner.lock().stats())
+}
+
+/// Reports heap usage over serial.
+pub fn print_heap_stats() {
+    let stats = heap_stats();
+    info!(
+        "Heap: {} of {} KiB used by {} allocations, {} free blocks (largest {} KiB)",
+        stats.used / 1024,
+        stats.total / 1024,
+        stats.allocations,
+        stats.free_blocks,
+        stats.largest_free_block / 1024
+    );
+}
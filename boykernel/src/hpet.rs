@@ -0,0 +1,252 @@
+use core::fmt;
+
+use log::{error, info};
+use spin::Once;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::{
+    acpi::{ACPI, GenericAddress, Polarity, TriggerMode},
+    ioapic::{IRQ_COUNT, IrqError, IrqHandler, register_irq_with_mode, unregister_irq},
+    paging::{CacheType, map_mmio},
+};
+
+const FEMTOS_PER_SECOND: u64 = 1_000_000_000_000_000;
+const FEMTOS_PER_NANO: u64 = 1_000_000;
+
+const GENERAL_CAPABILITIES: usize = 0x000;
+const GENERAL_CONFIG: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0F0;
+const ENABLE_CNF: u64 = 1 << 0;
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+
+const TIMER_INT_ENB: u64 = 1 << 2;
+const TIMER_TYPE_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAP: u64 = 1 << 4;
+const TIMER_VAL_SET: u64 = 1 << 6;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1F << TIMER_ROUTE_SHIFT;
+
+/// GSIs below this are taken by ISA IRQs and their overrides.
+const FIRST_FREE_GSI: u32 = 16;
+
+static HPET: Once<Hpet> = Once::new();
+
+#[derive(Debug)]
+pub enum HpetError {
+    NoHpet,
+    NoSuchTimer(u8),
+    /// The comparator can only fire once.
+    NotPeriodic(u8),
+    /// None of the GSIs the comparator can raise has a vector.
+    NoRoute(u8),
+    Irq(IrqError),
+}
+
+impl fmt::Display for HpetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HpetError::NoHpet => write!(f, "there is no HPET"),
+            HpetError::NoSuchTimer(timer) => write!(f, "HPET timer {} does not exist", timer),
+            HpetError::NotPeriodic(timer) => {
+                write!(f, "HPET timer {} cannot run periodically", timer)
+            }
+            HpetError::NoRoute(timer) => write!(f, "HPET timer {} has no usable GSI", timer),
+            HpetError::Irq(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// How an HPET comparator fires.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    /// Once, `ns` nanoseconds from now.
+    OneShot { ns: u64 },
+    /// Every `ns` nanoseconds.
+    Periodic { ns: u64 },
+}
+
+pub struct Hpet {
+    base: VirtAddr,
+    /// Length of one main counter tick in femtoseconds.
+    period_fs: u64,
+    timers: u8,
+    counter_64bit: bool,
+}
+
+impl Hpet {
+    fn read(&self, register: usize) -> u64 {
+        unsafe { core::ptr::read_volatile(self.base.as_ptr::<u64>().byte_add(register)) }
+    }
+
+    fn write(&self, register: usize, value: u64) {
+        unsafe {
+            core::ptr::write_volatile(self.base.as_mut_ptr::<u64>().byte_add(register), value)
+        }
+    }
+
+    fn timer_config(timer: u8) -> usize {
+        0x100 + 0x20 * timer as usize
+    }
+
+    fn timer_comparator(timer: u8) -> usize {
+        0x108 + 0x20 * timer as usize
+    }
+
+    /// Main counter ticks per second.
+    pub fn frequency(&self) -> u64 {
+        FEMTOS_PER_SECOND / self.period_fs
+    }
+
+    pub fn counter(&self) -> u64 {
+        self.read(MAIN_COUNTER)
+    }
+
+    /// A 32-bit main counter wraps within minutes, too soon to keep time with.
+    pub fn has_64bit_counter(&self) -> bool {
+        self.counter_64bit
+    }
+
+    fn ns_to_ticks(&self, ns: u64) -> u64 {
+        (ns as u128 * FEMTOS_PER_NANO as u128 / self.period_fs as u128).max(1) as u64
+    }
+
+    /// Ticks from `start` to `now`, across at most one wrap of the main counter.
+    fn elapsed(&self, start: u64, now: u64) -> u64 {
+        // The upper half of a 32-bit counter reads as 0, so it wraps at 2^32.
+        if self.counter_64bit {
+            now.wrapping_sub(start)
+        } else {
+            (now as u32).wrapping_sub(start as u32) as u64
+        }
+    }
+
+    /// Busy-waits `ms` milliseconds on the main counter.
+    pub fn wait_ms(&self, ms: u64) {
+        let start = self.counter();
+        let ticks = self.ns_to_ticks(ms * 1_000_000);
+        while self.elapsed(start, self.counter()) < ticks {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Maps and starts the HPET described by the ACPI HPET table, if there is one.
+pub fn init_hpet() {
+    let Some(table) = ACPI.get().and_then(|acpi| acpi.hpet) else {
+        info!("No HPET");
+        return;
+    };
+    if table.address.address_space != GenericAddress::SYSTEM_MEMORY {
+        error!("HPET at {} is not memory mapped", table.address);
+        return;
+    }
+
+    let base = map_mmio(
+        PhysAddr::new(table.address.address),
+        0x400,
+        CacheType::Uncacheable,
+    );
+    let mut hpet = Hpet {
+        base,
+        period_fs: 0,
+        timers: 0,
+        counter_64bit: false,
+    };
+    let capabilities = hpet.read(GENERAL_CAPABILITIES);
+    hpet.period_fs = capabilities >> 32;
+    hpet.timers = ((capabilities >> 8) & 0x1F) as u8 + 1;
+    hpet.counter_64bit = capabilities & COUNT_SIZE_CAP != 0;
+    // The spec caps the period at 100 ns, anything else means a bogus mapping.
+    if hpet.period_fs == 0 || hpet.period_fs > 100 * FEMTOS_PER_NANO {
+        error!("HPET reports a bogus period of {} fs", hpet.period_fs);
+        return;
+    }
+
+    for timer in 0..hpet.timers {
+        let config = hpet.read(Hpet::timer_config(timer));
+        hpet.write(Hpet::timer_config(timer), config & !TIMER_INT_ENB);
+    }
+    let config = hpet.read(GENERAL_CONFIG);
+    hpet.write(GENERAL_CONFIG, config | ENABLE_CNF);
+
+    info!(
+        "HPET at 0x{:x}: {} Hz, {} timers, {}-bit counter",
+        table.address.address,
+        hpet.frequency(),
+        hpet.timers,
+        if hpet.counter_64bit { 64 } else { 32 }
+    );
+    HPET.call_once(|| hpet);
+}
+
+pub fn hpet() -> Option<&'static Hpet> {
+    HPET.get()
+}
+
+/// Arms comparator `timer` to run `handler` in `mode`.
+///
+/// The comparator raises the first GSI above the ISA range it can reach, which is
+/// registered with the I/O APIC as an edge-triggered IRQ. A timer has to be stopped
+/// with [`stop_timer`] before it can be armed again.
+#[allow(dead_code)]
+pub fn start_timer(timer: u8, mode: TimerMode, handler: IrqHandler) -> Result<(), HpetError> {
+    let hpet = HPET.get().ok_or(HpetError::NoHpet)?;
+    if timer >= hpet.timers {
+        return Err(HpetError::NoSuchTimer(timer));
+    }
+
+    let config_register = Hpet::timer_config(timer);
+    let config = hpet.read(config_register);
+    if matches!(mode, TimerMode::Periodic { .. }) && config & TIMER_PERIODIC_CAP == 0 {
+        return Err(HpetError::NotPeriodic(timer));
+    }
+    let routes = (config >> 32) as u32;
+    let gsi = (FIRST_FREE_GSI..IRQ_COUNT as u32)
+        .find(|gsi| routes & (1 << gsi) != 0)
+        .ok_or(HpetError::NoRoute(timer))?;
+
+    register_irq_with_mode(gsi as u8, Polarity::ActiveHigh, TriggerMode::Edge, handler)
+        .map_err(HpetError::Irq)?;
+
+    // The interrupt stays off until the comparator holds the new deadline, a stale
+    // one could fire the handler early.
+    let mut config = (config & !(TIMER_ROUTE_MASK | TIMER_TYPE_PERIODIC | TIMER_INT_ENB))
+        | (gsi as u64) << TIMER_ROUTE_SHIFT;
+    match mode {
+        TimerMode::OneShot { ns } => {
+            hpet.write(config_register, config);
+            let deadline = hpet.counter() + hpet.ns_to_ticks(ns);
+            hpet.write(Hpet::timer_comparator(timer), deadline);
+        }
+        TimerMode::Periodic { ns } => {
+            // With VAL_SET the first write sets the comparator and the second one
+            // the period it is advanced by.
+            config |= TIMER_TYPE_PERIODIC;
+            hpet.write(config_register, config | TIMER_VAL_SET);
+            let period = hpet.ns_to_ticks(ns);
+            hpet.write(Hpet::timer_comparator(timer), hpet.counter() + period);
+            hpet.write(Hpet::timer_comparator(timer), period);
+        }
+    }
+    hpet.write(config_register, config | TIMER_INT_ENB);
+    Ok(())
+}
+
+/// Disarms comparator `timer` and releases its IRQ.
+#[allow(dead_code)]
+pub fn stop_timer(timer: u8) -> Result<(), HpetError> {
+    let hpet = HPET.get().ok_or(HpetError::NoHpet)?;
+    if timer >= hpet.timers {
+        return Err(HpetError::NoSuchTimer(timer));
+    }
+
+    let config_register = Hpet::timer_config(timer);
+    let config = hpet.read(config_register);
+    hpet.write(config_register, config & !TIMER_INT_ENB);
+    if config & TIMER_INT_ENB != 0 {
+        let gsi = (config & TIMER_ROUTE_MASK) >> TIMER_ROUTE_SHIFT;
+        unregister_irq(gsi as u8).map_err(HpetError::Irq)?;
+    }
+    Ok(())
+}
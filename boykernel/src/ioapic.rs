@@ -0,0 +1,324 @@
+use core::fmt;
+
+use alloc::vec::Vec;
+use log::{error, info, warn};
+use spin::{Mutex, Once};
+use x86_64::{
+    PhysAddr, VirtAddr, instructions::interrupts::without_interrupts,
+    structures::idt::InterruptStackFrame,
+};
+
+use crate::{
+    acpi::{Polarity, TriggerMode, madt},
+    bk_interrupts::{end_of_interrupt, local_apic_id},
+    paging::{CacheType, map_mmio},
+};
+
+/// IRQ `n` is delivered on vector `IRQ_VECTOR_BASE + n`.
+pub const IRQ_VECTOR_BASE: u8 = 0x30;
+/// Number of IRQs with a vector: the 16 ISA IRQs and the PCI GSIs of a standard
+/// 24-pin I/O APIC.
+pub const IRQ_COUNT: usize = 24;
+const ISA_IRQS: u8 = 16;
+/// Where the I/O APIC sits on a standard PC, used when there is no MADT.
+const DEFAULT_IO_APIC: u64 = 0xFEC0_0000;
+
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+pub type IrqHandler = fn();
+
+static IO_APICS: Once<Mutex<Vec<IoApic>>> = Once::new();
+static IRQ_HANDLERS: Mutex<[Option<Registration>; IRQ_COUNT]> = Mutex::new([None; IRQ_COUNT]);
+
+/// A registered IRQ and the GSI whose redirection entry it programmed.
+#[derive(Clone, Copy)]
+struct Registration {
+    handler: IrqHandler,
+    gsi: u32,
+}
+
+#[derive(Debug)]
+pub enum IrqError {
+    /// Only IRQs below [`IRQ_COUNT`] have a vector.
+    OutOfRange(u8),
+    /// No I/O APIC handles the GSI the IRQ is wired to.
+    NoIoApic { irq: u8, gsi: u32 },
+    /// This IRQ, or the one given here, already owns the GSI the IRQ is wired to.
+    /// An override like IRQ 0 -> GSI 2 makes two IRQ numbers share one line.
+    AlreadyRegistered(u8),
+}
+
+impl fmt::Display for IrqError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IrqError::OutOfRange(irq) => write!(f, "IRQ {} is out of range", irq),
+            IrqError::NoIoApic { irq, gsi } => {
+                write!(f, "no I/O APIC handles GSI {} (IRQ {})", gsi, irq)
+            }
+            IrqError::AlreadyRegistered(irq) => write!(f, "IRQ {} already has a handler", irq),
+        }
+    }
+}
+
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    pins: u32,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        let base = self.base.as_mut_ptr::<u32>();
+        unsafe {
+            core::ptr::write_volatile(base.byte_add(IOREGSEL), register);
+            core::ptr::read_volatile(base.byte_add(IOWIN))
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        let base = self.base.as_mut_ptr::<u32>();
+        unsafe {
+            core::ptr::write_volatile(base.byte_add(IOREGSEL), register);
+            core::ptr::write_volatile(base.byte_add(IOWIN), value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.pins).contains(&gsi)
+    }
+
+    fn read_redirection(&self, pin: u32) -> u64 {
+        let low = self.read(IOREDTBL + pin * 2) as u64;
+        let high = self.read(IOREDTBL + pin * 2 + 1) as u64;
+        high << 32 | low
+    }
+
+    /// Writes the high half first, so the entry is never live with a stale destination.
+    fn write_redirection(&self, pin: u32, entry: u64) {
+        self.write(IOREDTBL + pin * 2 + 1, (entry >> 32) as u32);
+        self.write(IOREDTBL + pin * 2, entry as u32);
+    }
+}
+
+/// Where an IRQ ends up: its GSI and how the line is signalled.
+struct Route {
+    gsi: u32,
+    polarity: Polarity,
+    trigger: TriggerMode,
+}
+
+/// Applies the MADT interrupt source overrides. ISA IRQs default to active high and
+/// edge triggered, the IRQs above them are PCI GSIs, active low and level triggered.
+fn route(irq: u8) -> Route {
+    let isa = irq < ISA_IRQS;
+    let (gsi, polarity, trigger) = madt()
+        .and_then(|madt| madt.overrides.iter().find(|o| isa && o.source == irq))
+        .map_or(
+            (irq as u32, Polarity::BusDefault, TriggerMode::BusDefault),
+            |o| (o.gsi, o.polarity, o.trigger),
+        );
+
+    let polarity = match polarity {
+        Polarity::BusDefault if isa => Polarity::ActiveHigh,
+        Polarity::BusDefault => Polarity::ActiveLow,
+        polarity => polarity,
+    };
+    let trigger = match trigger {
+        TriggerMode::BusDefault if isa => TriggerMode::Edge,
+        TriggerMode::BusDefault => TriggerMode::Level,
+        trigger => trigger,
+    };
+    Route {
+        gsi,
+        polarity,
+        trigger,
+    }
+}
+
+/// Runs `f` on the I/O APIC `irq` is wired to, with the pin it arrives on.
+fn with_io_apic<R>(irq: u8, f: impl FnOnce(&IoApic, u32) -> R) -> Result<R, IrqError> {
+    let gsi = route(irq).gsi;
+    let io_apics = IO_APICS
+        .get()
+        .ok_or(IrqError::NoIoApic { irq, gsi })?
+        .lock();
+    let io_apic = io_apics
+        .iter()
+        .find(|io_apic| io_apic.handles(gsi))
+        .ok_or(IrqError::NoIoApic { irq, gsi })?;
+    Ok(f(io_apic, gsi - io_apic.gsi_base))
+}
+
+/// Maps the I/O APICs listed in the MADT and masks all of their pins.
+///
+/// Must run after the local APIC is enabled, since IRQs are sent to its APIC ID.
+pub fn init_io_apics() {
+    let io_apics: Vec<(u64, u32)> = match madt() {
+        Some(madt) => madt
+            .io_apics
+            .iter()
+            .map(|io_apic| (io_apic.address as u64, io_apic.gsi_base))
+            .collect(),
+        None => Vec::from([(DEFAULT_IO_APIC, 0)]),
+    };
+    if io_apics.is_empty() {
+        warn!("No I/O APIC, device interrupts will not arrive");
+    }
+
+    let io_apics = io_apics
+        .into_iter()
+        .map(|(address, gsi_base)| {
+            let base = map_mmio(PhysAddr::new(address), 0x20, CacheType::Uncacheable);
+            let mut io_apic = IoApic {
+                base,
+                gsi_base,
+                pins: 0,
+            };
+            io_apic.pins = ((io_apic.read(IOAPICVER) >> 16) & 0xFF) + 1;
+            for pin in 0..io_apic.pins {
+                io_apic.write_redirection(pin, REDIRECTION_MASKED);
+            }
+            info!(
+                "I/O APIC at 0x{:x}: GSIs {}-{}",
+                address,
+                gsi_base,
+                gsi_base + io_apic.pins - 1
+            );
+            io_apic
+        })
+        .collect();
+    IO_APICS.call_once(|| Mutex::new(io_apics));
+}
+
+/// Installs `handler` for `irq` and unmasks it.
+///
+/// ISA IRQs are routed through the MADT interrupt source overrides, so drivers can
+/// use the IRQ numbers they know (1 for the keyboard, 4 for COM1, 8 for the RTC).
+/// The handler runs in interrupt context, the end of interrupt is sent for it.
+pub fn register_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    let route = route(irq);
+    register_irq_with_mode(irq, route.polarity, route.trigger, handler)
+}
+
+/// Like [`register_irq`], for devices that choose how they signal the line
+/// themselves instead of following the bus defaults.
+pub fn register_irq_with_mode(
+    irq: u8,
+    polarity: Polarity,
+    trigger: TriggerMode,
+    handler: IrqHandler,
+) -> Result<(), IrqError> {
+    if irq as usize >= IRQ_COUNT {
+        return Err(IrqError::OutOfRange(irq));
+    }
+
+    without_interrupts(|| {
+        let gsi = route(irq).gsi;
+        let mut handlers = IRQ_HANDLERS.lock();
+        if handlers[irq as usize].is_some() {
+            return Err(IrqError::AlreadyRegistered(irq));
+        }
+        // Redirection entries belong to GSIs, so another IRQ on the same one would
+        // silently take it over.
+        if let Some(owner) = handlers
+            .iter()
+            .position(|registration| registration.is_some_and(|r| r.gsi == gsi))
+        {
+            return Err(IrqError::AlreadyRegistered(owner as u8));
+        }
+
+        let mut entry = (IRQ_VECTOR_BASE + irq) as u64 | (local_apic_id() as u64) << 56;
+        if polarity == Polarity::ActiveLow {
+            entry |= REDIRECTION_ACTIVE_LOW;
+        }
+        if trigger == TriggerMode::Level {
+            entry |= REDIRECTION_LEVEL;
+        }
+        with_io_apic(irq, |io_apic, pin| io_apic.write_redirection(pin, entry))?;
+        handlers[irq as usize] = Some(Registration { handler, gsi });
+
+        info!(
+            "IRQ {} -> GSI {} ({:?}, {:?}) on vector 0x{:x}",
+            irq,
+            gsi,
+            polarity,
+            trigger,
+            IRQ_VECTOR_BASE + irq
+        );
+        Ok(())
+    })
+}
+
+/// Masks `irq` and removes its handler, so it can be registered again.
+#[allow(dead_code)]
+pub fn unregister_irq(irq: u8) -> Result<(), IrqError> {
+    if irq as usize >= IRQ_COUNT {
+        return Err(IrqError::OutOfRange(irq));
+    }
+    without_interrupts(|| {
+        set_masked(irq, true)?;
+        IRQ_HANDLERS.lock()[irq as usize] = None;
+        Ok(())
+    })
+}
+
+fn set_masked(irq: u8, masked: bool) -> Result<(), IrqError> {
+    with_io_apic(irq, |io_apic, pin| {
+        let entry = io_apic.read_redirection(pin);
+        let entry = if masked {
+            entry | REDIRECTION_MASKED
+        } else {
+            entry & !REDIRECTION_MASKED
+        };
+        io_apic.write_redirection(pin, entry);
+    })
+}
+
+/// Stops `irq` from being delivered until [`unmask`] is called.
+#[allow(dead_code)]
+pub fn mask(irq: u8) -> Result<(), IrqError> {
+    set_masked(irq, true)
+}
+
+#[allow(dead_code)]
+pub fn unmask(irq: u8) -> Result<(), IrqError> {
+    set_masked(irq, false)
+}
+
+fn dispatch_irq(irq: u8) {
+    // Copied out so the handler may mask or unmask IRQs itself.
+    let handler = IRQ_HANDLERS.lock()[irq as usize].map(|registration| registration.handler);
+    match handler {
+        Some(handler) => handler(),
+        None => error!("Unhandled IRQ {}", irq),
+    }
+    end_of_interrupt();
+}
+
+macro_rules! irq_entries {
+    ($($irq:literal => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                dispatch_irq($irq);
+            }
+        )*
+
+        /// IDT entries for the IRQ vectors, IRQ `n` at index `n`.
+        pub const IRQ_ENTRIES: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_COUNT] =
+            [$($name),*];
+    };
+}
+
+irq_entries! {
+    0 => irq0, 1 => irq1, 2 => irq2, 3 => irq3, 4 => irq4, 5 => irq5,
+    6 => irq6, 7 => irq7, 8 => irq8, 9 => irq9, 10 => irq10, 11 => irq11,
+    12 => irq12, 13 => irq13, 14 => irq14, 15 => irq15, 16 => irq16, 17 => irq17,
+    18 => irq18, 19 => irq19, 20 => irq20, 21 => irq21, 22 => irq22, 23 => irq23,
+}
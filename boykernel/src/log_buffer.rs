@@ -0,0 +1,177 @@
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use log::{Level, Record};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    RENDERER,
+    console::ScreenWriter,
+    exceptions::fatal_println,
+    time::{Uptime, now},
+};
+
+/// Records kept, the oldest ones are overwritten first.
+const LOG_CAPACITY: usize = 256;
+/// Longer messages are cut off.
+const MESSAGE_LEN: usize = 192;
+const TARGET_LEN: usize = 24;
+
+static LOG_BUFFER: Mutex<LogBuffer> = Mutex::new(LogBuffer {
+    entries: heapless::Deque::new(),
+    next_sequence: 0,
+});
+/// Records lost because the buffer was busy when they were logged.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// One log record as it was logged.
+pub struct LogEntry {
+    /// Counts up from 0 over all records ever logged, so readers can tell where they
+    /// left off and how many were overwritten since.
+    pub sequence: u64,
+    /// Nanoseconds since boot.
+    pub timestamp: u64,
+    pub level: Level,
+    pub target: heapless::String<TARGET_LEN>,
+    pub message: heapless::String<MESSAGE_LEN>,
+}
+
+/// Formats like `dmesg`: `[    1.234567] INFO  acpi: message`.
+impl fmt::Display for LogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{}] {:<5} {}: {}",
+            Uptime(self.timestamp),
+            self.level,
+            self.target,
+            self.message
+        )
+    }
+}
+
+struct LogBuffer {
+    entries: heapless::Deque<LogEntry, LOG_CAPACITY>,
+    next_sequence: u64,
+}
+
+/// Keeps as much of the text as fits instead of failing like `heapless::String`.
+struct Truncate<'a, const N: usize> {
+    string: &'a mut heapless::String<N>,
+    /// Set at the first character that did not fit. Later pieces of the text are
+    /// dropped too, even short ones, so nothing is glued onto the cut.
+    full: bool,
+}
+
+impl<'a, const N: usize> Truncate<'a, N> {
+    fn new(string: &'a mut heapless::String<N>) -> Self {
+        Self {
+            string,
+            full: false,
+        }
+    }
+}
+
+impl<const N: usize> Write for Truncate<'_, N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.full {
+                break;
+            }
+            self.full = self.string.push(c).is_err();
+        }
+        Ok(())
+    }
+}
+
+/// Stores `record` under `target`, called by the logger for every record it lets
+/// through.
+pub fn record(record: &Record, target: &str) {
+    let mut entry = LogEntry {
+        sequence: 0,
+        timestamp: now(),
+        level: record.level(),
+        target: heapless::String::new(),
+        message: heapless::String::new(),
+    };
+    let _ = Truncate::new(&mut entry.target).write_str(target);
+    let _ = Truncate::new(&mut entry.message).write_fmt(*record.args());
+
+    without_interrupts(|| {
+        // Only an exception or NMI handler logging on top of another record finds
+        // the buffer locked, and it must not wait for it.
+        let Some(mut buffer) = LOG_BUFFER.try_lock() else {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+        entry.sequence = buffer.next_sequence;
+        buffer.next_sequence += 1;
+        if buffer.entries.is_full() {
+            buffer.entries.pop_front();
+        }
+        let _ = buffer.entries.push_back(entry);
+    });
+}
+
+/// Calls `visit` with every stored record whose sequence number is at least
+/// `from`, oldest first, and returns the sequence number to continue from.
+#[allow(dead_code)]
+pub fn read_log(from: u64, mut visit: impl FnMut(&LogEntry)) -> u64 {
+    without_interrupts(|| {
+        let buffer = LOG_BUFFER.lock();
+        buffer
+            .entries
+            .iter()
+            .filter(|entry| entry.sequence >= from)
+            .for_each(&mut visit);
+        buffer.next_sequence
+    })
+}
+
+/// Writes every stored record to `out`, one per line, like `dmesg`.
+#[allow(dead_code)]
+pub fn dmesg(out: &mut impl Write) -> fmt::Result {
+    let mut result = Ok(());
+    read_log(0, |entry| {
+        if result.is_ok() {
+            result = writeln!(out, "{}", entry);
+        }
+    });
+    result
+}
+
+/// Draws the stored records at `level` or above on screen, for whatever was logged
+/// before the screen could show it or while it was being redrawn.
+pub fn replay_to_screen(level: Level) {
+    let Some(renderer) = RENDERER.get() else {
+        return;
+    };
+    without_interrupts(|| {
+        let renderer = renderer.lock();
+        let buffer = LOG_BUFFER.lock();
+        for entry in buffer.entries.iter().filter(|entry| entry.level <= level) {
+            let _ = writeln!(ScreenWriter(&renderer), "{}", entry);
+        }
+    });
+}
+
+/// Prints the last `count` records to serial and screen, for the panic handler.
+pub fn dump_log(count: usize) {
+    // The panicking code may have been logging.
+    let Some(buffer) = LOG_BUFFER.try_lock() else {
+        fatal_println(format_args!("The log buffer is busy"));
+        return;
+    };
+    fatal_println(format_args!("Last log messages:"));
+    let skip = buffer.entries.len().saturating_sub(count);
+    for entry in buffer.entries.iter().skip(skip) {
+        fatal_println(format_args!("{}", entry));
+    }
+    let dropped = DROPPED.load(Ordering::Relaxed);
+    if dropped != 0 {
+        fatal_println(format_args!("({} records were dropped)", dropped));
+    }
+}
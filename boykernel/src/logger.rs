@@ -0,0 +1,204 @@
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use boyinfo::CommandLine;
+use log::{Level, LevelFilter, Log, Metadata, Record, warn};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    RENDERER,
+    console::ScreenWriter,
+    gop_render::CURSOR_STATE,
+    log_buffer,
+    serial::{SerialWriter, write_timestamp},
+};
+
+/// Level of every module without a level of its own, unless `log=` says otherwise.
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+/// Records at least this severe also show up on screen.
+pub const SCREEN_LEVEL: Level = Level::Warn;
+const MAX_DIRECTIVES: usize = 8;
+const MAX_MODULE_LEN: usize = 48;
+/// Left out of targets, every kernel module starts with it.
+const CRATE_PREFIX: &str = "boykernel::";
+
+static LOGGER: KernelLogger = KernelLogger;
+/// [`LevelFilter`] of modules without a directive, stored as its discriminant.
+static LEVEL: AtomicUsize = AtomicUsize::new(DEFAULT_LEVEL as usize);
+static DIRECTIVES: Mutex<heapless::Vec<Directive, MAX_DIRECTIVES>> =
+    Mutex::new(heapless::Vec::new());
+
+/// The level of one module and its submodules.
+struct Directive {
+    module: heapless::String<MAX_MODULE_LEN>,
+    level: LevelFilter,
+}
+
+/// Why a log level could not be set.
+#[derive(Debug, Clone, Copy)]
+pub enum LogError {
+    TooManyModules,
+    ModuleNameTooLong,
+}
+
+impl fmt::Display for LogError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogError::TooManyModules => {
+                write!(
+                    f,
+                    "at most {} modules can have their own level",
+                    MAX_DIRECTIVES
+                )
+            }
+            LogError::ModuleNameTooLong => {
+                write!(f, "module names are at most {} bytes", MAX_MODULE_LEN)
+            }
+        }
+    }
+}
+
+fn short_target(target: &str) -> &str {
+    target.strip_prefix(CRATE_PREFIX).unwrap_or(target)
+}
+
+fn level_from_index(index: usize) -> LevelFilter {
+    LevelFilter::iter().nth(index).unwrap_or(DEFAULT_LEVEL)
+}
+
+/// The level of the most specific directive covering `target`.
+fn level_for(target: &str) -> LevelFilter {
+    let default = level_from_index(LEVEL.load(Ordering::Relaxed));
+    // A handler that interrupted a level change gets the default level.
+    let Some(directives) = DIRECTIVES.try_lock() else {
+        return default;
+    };
+    let target = short_target(target);
+    directives
+        .iter()
+        .filter(|directive| {
+            target
+                .strip_prefix(directive.module.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+        })
+        .max_by_key(|directive| directive.module.len())
+        .map_or(default, |directive| directive.level)
+}
+
+/// Lets through everything that any directive enables, `enabled` filters the rest.
+fn update_max_level() {
+    let default = level_from_index(LEVEL.load(Ordering::Relaxed));
+    let max = without_interrupts(|| {
+        DIRECTIVES
+            .lock()
+            .iter()
+            .map(|directive| directive.level)
+            .fold(default, core::cmp::max)
+    });
+    log::set_max_level(max);
+}
+
+struct KernelLogger;
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        log_buffer::record(record, short_target(record.target()));
+        write_timestamp();
+        let _ = writeln!(
+            SerialWriter,
+            "[{} {}] {}",
+            record.level(),
+            short_target(record.target()),
+            record.args()
+        );
+        if record.level() <= SCREEN_LEVEL {
+            print_on_screen(record);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Skips the screen instead of waiting for it, serial has the record anyway.
+fn print_on_screen(record: &Record) {
+    if CURSOR_STATE.is_locked() {
+        return;
+    }
+    if let Some(renderer) = RENDERER.get().and_then(|renderer| renderer.try_lock()) {
+        let _ = writeln!(
+            ScreenWriter(&renderer),
+            "[{}] {}",
+            record.level(),
+            record.args()
+        );
+    }
+}
+
+/// Routes the `log` macros to serial and the screen. Must run before anything logs.
+pub fn init_logger() {
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(DEFAULT_LEVEL);
+}
+
+/// Sets the level of every module without a level of its own.
+pub fn set_level(level: LevelFilter) {
+    LEVEL.store(level as usize, Ordering::Relaxed);
+    update_max_level();
+}
+
+/// Sets the level of `module` (e.g. `acpi` or `boykernel::acpi`) and its submodules.
+pub fn set_module_level(module: &str, level: LevelFilter) -> Result<(), LogError> {
+    let module = short_target(module);
+    without_interrupts(|| {
+        let mut directives = DIRECTIVES.lock();
+        if let Some(directive) = directives
+            .iter_mut()
+            .find(|directive| directive.module == module)
+        {
+            directive.level = level;
+            return Ok(());
+        }
+        let module = heapless::String::try_from(module).map_err(|_| LogError::ModuleNameTooLong)?;
+        directives
+            .push(Directive { module, level })
+            .map_err(|_| LogError::TooManyModules)
+    })?;
+    update_max_level();
+    Ok(())
+}
+
+/// Applies `log=` from the command line, a comma separated list of levels for
+/// everything (`log=debug`) or for single modules (`log=warn,acpi=trace`).
+pub fn configure_logging(cmdline: &CommandLine) {
+    let Some(value) = cmdline.value("log") else {
+        return;
+    };
+    for directive in value.split(',').filter(|directive| !directive.is_empty()) {
+        let (module, level) = match directive.split_once('=') {
+            Some((module, level)) => (Some(module), level),
+            None => (None, directive),
+        };
+        let Ok(level) = level.parse::<LevelFilter>() else {
+            warn!("Unknown log level \"{}\" in log={}", level, value);
+            continue;
+        };
+        match module {
+            None => set_level(level),
+            Some(module) => {
+                if let Err(err) = set_module_level(module, level) {
+                    warn!("Cannot set the log level of {}: {}", module, err);
+                }
+            }
+        }
+    }
+}
@@ -2,111 +2,61 @@
 #![no_std]
 #![no_main]
 
-use core::{
-    alloc::{GlobalAlloc, Layout},
-    arch::asm,
-    cell::UnsafeCell,
-    mem::MaybeUninit,
-    sync::atomic::{AtomicUsize, Ordering},
-};
+use core::arch::asm;
 use spin::{Mutex, Once};
-use x86_64::instructions::interrupts::enable;
-
-#[allow(unused_imports)] // Falsely reports as unused for some reason
-use alloc::string::ToString;
+use x86_64::instructions::interrupts::{disable, enable};
 
 extern crate alloc;
 
+use boyinfo::{BootInfo, MemoryRegionKind};
+use log::{info, warn};
+
 use crate::{
+    acpi::{dump, init_acpi},
     beep::beep,
     bk_interrupts::{enable_apic, init_idt, test_interrupts},
-    framebuffer::FramebufferInfo,
+    boot_info::{init_boot_info, validate_boot_info},
+    exceptions::init_exceptions,
+    frame_allocator::{get_and_lock_frame_allocator, init_frame_allocator, print_memory_stats},
+    framebuffer::map_framebuffer,
+    gdt::init_gdt,
     gop_render::SimplifiedRenderer,
-    serial::info,
+    heap::init_heap,
+    hpet::init_hpet,
+    ioapic::init_io_apics,
+    log_buffer::replay_to_screen,
+    logger::{SCREEN_LEVEL, configure_logging, init_logger},
+    paging::{PHYS_MEM_OFFSET, finish_paging_switch, init_paging},
+    rtc::init_rtc,
+    serial::{COM1, DEFAULT_BAUD, configure_serial, enable_serial_interrupts, init_serial},
 };
 
+mod acpi;
+mod backtrace;
 mod beep;
 mod bk_interrupts;
+mod boot_info;
+mod console;
+mod exceptions;
 mod font;
+mod frame_allocator;
 mod framebuffer;
+mod gdt;
 mod gop_render;
+mod heap;
+mod hpet;
+mod ioapic;
+mod log_buffer;
+mod logger;
 pub mod memory;
+mod paging;
+mod panic;
+mod rtc;
 mod serial;
-mod strings;
+mod time;
 mod utils;
 mod watermark;
 
-const HEAP_SIZE: usize = 4096;
-
-struct GayAllocator {
-    heap: UnsafeCell<[MaybeUninit<u8>; HEAP_SIZE]>,
-    offset: AtomicUsize,
-}
-
-unsafe impl GlobalAlloc for GayAllocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        unsafe {
-            let size = layout.size();
-            let align = layout.align();
-
-            let heap_start = self.heap.get().cast::<u8>();
-            loop {
-                let orig_offset = self.offset.load(Ordering::Relaxed);
-                let ptr = heap_start.add(orig_offset);
-
-                let offset = ptr.align_offset(align);
-                if offset == usize::MAX {
-                    return core::ptr::null_mut();
-                }
-
-                let alloc = ptr.add(offset);
-                if alloc.offset_from(heap_start) as usize > HEAP_SIZE {
-                    return core::ptr::null_mut();
-                }
-
-                if self
-                    .offset
-                    .compare_exchange_weak(
-                        orig_offset,
-                        orig_offset + offset + size,
-                        Ordering::Relaxed,
-                        Ordering::Relaxed,
-                    )
-                    .is_ok()
-                {
-                    return alloc;
-                } else {
-                    continue;
-                }
-            }
-        }
-    }
-
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        unsafe {
-            let size = layout.size();
-            let heap_start = self.heap.get().cast::<u8>();
-            let start_of_alloc = ptr.offset_from(heap_start) as usize;
-            let end_of_alloc = ptr.add(size).offset_from(heap_start) as usize;
-
-            _ = self.offset.compare_exchange(
-                end_of_alloc,
-                start_of_alloc,
-                Ordering::Relaxed,
-                Ordering::Relaxed,
-            );
-        }
-    }
-}
-
-unsafe impl Sync for GayAllocator {}
-
-#[global_allocator]
-static GLOBAL: GayAllocator = GayAllocator {
-    heap: UnsafeCell::new([const { MaybeUninit::uninit() }; HEAP_SIZE]),
-    offset: AtomicUsize::new(0),
-};
-
 // Global Once to hold the Mutex for the renderer
 pub static RENDERER: Once<Mutex<SimplifiedRenderer>> = Once::new();
 
@@ -116,69 +66,83 @@ pub fn get_and_lock_renderer() -> spin::MutexGuard<'static, SimplifiedRenderer<'
 }
 
 #[unsafe(no_mangle)]
-pub extern "C" fn _start(fb: &'static FramebufferInfo) -> ! {
-    info("Kernel successfully jumped to!");
+pub extern "sysv64" fn _start(boot_info: &'static mut BootInfo) -> ! {
+    disable();
+    let serial = init_serial(COM1, DEFAULT_BAUD);
+    init_logger();
+    info!("Kernel successfully jumped to!");
+    if let Err(err) = serial {
+        warn!("Serial console: {}", err);
+    }
 
-    let renderer = SimplifiedRenderer::new(fb);
-    info("Initializing global renderer");
-    RENDERER.call_once(|| Mutex::new(renderer));
+    validate_boot_info(boot_info);
+    init_frame_allocator(boot_info);
 
-    info("Enabling interrupts");
-    enable();
-    enable_apic();
-    info("Initializing IDT");
+    info!("Switching to kernel page tables");
+    init_paging(boot_info, kernel_main)
+}
+
+/// Continues booting from the higher half, on the kernel's own page tables and stack.
+extern "sysv64" fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    unsafe {
+        boot_info.rebase(PHYS_MEM_OFFSET);
+        get_and_lock_frame_allocator().rebase(PHYS_MEM_OFFSET);
+    }
+    finish_paging_switch();
+    init_gdt();
+    info!("Initializing IDT");
     init_idt();
+    init_exceptions();
 
-    let renderer = get_and_lock_renderer();
-    renderer.clear_screen();
-    renderer.show_alphabet();
-    renderer.show_watermark();
+    // Nothing uses the firmware page tables, stack, GDT or IDT anymore.
+    get_and_lock_frame_allocator().reclaim(boot_info, MemoryRegionKind::BootServices);
+    init_heap();
 
-    info("Running interrupts test");
-    test_interrupts();
+    let boot_info = init_boot_info(boot_info);
+    configure_logging(&boot_info.cmdline);
+    configure_serial(&boot_info.cmdline);
+    print_memory_stats();
 
-    beep(440, 1000);
-
-    loop {
-        unsafe { asm!("hlt") }
+    init_acpi(boot_info.rsdp_address);
+    if boot_info.cmdline.has_flag("acpi.dump") {
+        dump();
     }
-}
+    init_hpet();
 
-#[cfg(not(test))]
-#[panic_handler]
-fn panic(panic: &core::panic::PanicInfo) -> ! {
-    use serial::serial_write_str;
-
-    serial_write_str("Panic occurred: ");
-    serial_write_str("=== PANIC ===\n");
-
-    #[cfg(not(debug_assertions))]
-    serial_write_str("[WARNING] This is a release build, panic information may be limited.\n");
-
-    if let Some(location) = panic.location() {
-        serial_write_str("Location: ");
-        serial_write_str(location.file());
-        serial_write_str(":");
-        serial_write_str(location.line().to_string().as_str());
-        serial_write_str(":");
-        serial_write_str(location.column().to_string().as_str());
-        serial_write_str("\n");
+    if boot_info.framebuffer.is_usable() {
+        let renderer = SimplifiedRenderer::new(map_framebuffer(&boot_info.framebuffer));
+        info!("Initializing global renderer");
+        RENDERER.call_once(|| Mutex::new(renderer));
     } else {
-        serial_write_str("Location: <unknown>\n");
+        warn!(
+            "No usable framebuffer ({:?}), only logging to serial",
+            boot_info.framebuffer.format
+        );
     }
 
-    if let Some(message) = panic.message().as_str() {
-        serial_write_str("Message: ");
-        serial_write_str(message);
-        serial_write_str("\n");
-    } else {
-        serial_write_str("Message: <none>\n");
+    enable_apic();
+    init_rtc();
+    init_io_apics();
+    enable_serial_interrupts();
+    info!("Enabling interrupts");
+    enable();
+
+    if RENDERER.get().is_some() {
+        let renderer = get_and_lock_renderer();
+        renderer.clear_screen();
+        renderer.show_alphabet();
+        renderer.show_watermark();
+        drop(renderer);
+        // Warnings from before the renderer existed or from while it was redrawing.
+        replay_to_screen(SCREEN_LEVEL);
     }
 
-    serial_write_str("=============\n");
-    serial_write_str("\n\n");
+    info!("Running interrupts test");
+    test_interrupts();
+
+    beep(440, 1000);
 
     loop {
-        unsafe { core::arch::asm!("hlt") }
+        unsafe { asm!("hlt") }
     }
 }
@@ -0,0 +1,296 @@
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use boyinfo::{BootInfo, KernelImage, MemoryRegionKind, SegmentFlags};
+use spin::Mutex;
+use x86_64::{
+    PhysAddr, VirtAddr,
+    registers::{
+        control::{Cr0, Cr0Flags},
+        model_specific::{Efer, EferFlags, Msr},
+    },
+    structures::paging::{
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags,
+        PhysFrame, Size2MiB, Size4KiB, Translate,
+        mapper::{MapToError, UnmapError},
+    },
+};
+
+use crate::frame_allocator::{FRAME_SIZE, get_and_lock_frame_allocator};
+
+/// All of physical memory is mapped starting here.
+pub const PHYS_MEM_OFFSET: u64 = 0xFFFF_8000_0000_0000;
+/// Device memory mapped through [`map_mmio`] is placed from here upwards.
+const MMIO_BASE: u64 = 0xFFFF_FFFF_0000_0000;
+/// The boot stack sits right below the kernel, with an unmapped guard page under it.
+const KERNEL_STACK_BOTTOM: u64 = 0xFFFF_FFFF_7FF0_0000;
+const KERNEL_STACK_PAGES: u64 = 16;
+/// Higher-half alias of the kernel image.
+pub const KERNEL_VIRT_BASE: u64 = 0xFFFF_FFFF_8000_0000;
+
+const IA32_PAT: u32 = 0x277;
+/// Default PAT layout with entry 1 (PWT) turned from write-through into write-combining.
+const PAT_VALUE: u64 = 0x0007_0106_0007_0106;
+
+/// Memory type used for a mapping, selected through the PAT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheType {
+    WriteBack,
+    WriteCombining,
+    Uncacheable,
+}
+
+impl CacheType {
+    fn flags(self) -> PageTableFlags {
+        match self {
+            CacheType::WriteBack => PageTableFlags::empty(),
+            CacheType::WriteCombining => PageTableFlags::WRITE_THROUGH,
+            CacheType::Uncacheable => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+/// The kernel's page tables, available once [`init_paging`] switched to them.
+static KERNEL_PAGE_TABLE: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_BASE);
+
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(addr.as_u64() + PHYS_MEM_OFFSET)
+}
+
+/// Builds the kernel's own PML4 and switches to it.
+///
+/// The new tables map all of physical memory at [`PHYS_MEM_OFFSET`], a boot stack,
+/// and the kernel image at [`KERNEL_VIRT_BASE`]. The image is also kept where it
+/// was entered and at its link address, since absolute addresses baked into the
+/// binary point there. Each segment only gets the access its ELF flags allow.
+/// Execution then continues in `continuation` from the higher half, on the new
+/// stack, with `boot_info` passed through the offset mapping.
+///
+/// Must run with interrupts disabled, on the firmware's identity map, before anything
+/// uses the heap.
+pub fn init_paging(
+    boot_info: &'static mut BootInfo,
+    continuation: extern "sysv64" fn(&'static mut BootInfo) -> !,
+) -> ! {
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+        Msr::new(IA32_PAT).write(PAT_VALUE);
+    }
+
+    let mut frame_allocator = get_and_lock_frame_allocator();
+    let pml4_frame = frame_allocator
+        .allocate_frame()
+        .expect("No memory for the kernel PML4");
+
+    // The firmware identity maps everything, so the new tables are written through
+    // an offset of zero until they are live.
+    let pml4 = unsafe {
+        let pml4 = &mut *(pml4_frame.start_address().as_u64() as *mut PageTable);
+        pml4.zero();
+        pml4
+    };
+    let mut mapper = unsafe { OffsetPageTable::new(pml4, VirtAddr::new(0)) };
+    let allocator = &mut *frame_allocator;
+
+    map_physical_memory(&mut mapper, allocator, boot_info);
+
+    let image = boot_info.kernel;
+    map_kernel_image(&mut mapper, allocator, &image);
+
+    let stack_frames = allocator
+        .allocate_frames(KERNEL_STACK_PAGES as usize)
+        .expect("No memory for the kernel stack");
+    let stack_flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    for i in 0..KERNEL_STACK_PAGES {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(
+            KERNEL_STACK_BOTTOM + (i + 1) * FRAME_SIZE,
+        ));
+        let frame = stack_frames + i;
+        unsafe { mapper.map_to(page, frame, stack_flags, allocator) }
+            .expect("Failed to map the kernel stack")
+            .ignore();
+    }
+    let stack_top = KERNEL_STACK_BOTTOM + (KERNEL_STACK_PAGES + 1) * FRAME_SIZE;
+
+    drop(frame_allocator);
+
+    let entry = continuation as usize as u64 - image.phys_start + KERNEL_VIRT_BASE;
+    let boot_info = boot_info as *mut BootInfo as u64 + PHYS_MEM_OFFSET;
+    let pml4 = pml4_frame.start_address().as_u64();
+
+    // Function entry expects rsp to be 8 off a 16-byte boundary, as if `call` pushed
+    // a return address.
+    unsafe {
+        asm!(
+            "mov cr3, {pml4}",
+            "mov rsp, {stack}",
+            "xor ebp, ebp",
+            "jmp {entry}",
+            pml4 = in(reg) pml4,
+            stack = in(reg) stack_top - 8,
+            entry = in(reg) entry,
+            in("rdi") boot_info,
+            options(noreturn)
+        );
+    }
+}
+
+/// Maps every kernel segment with the permissions from its ELF flags, at its physical
+/// address, its link address and its [`KERNEL_VIRT_BASE`] alias.
+fn map_kernel_image(
+    mapper: &mut OffsetPageTable,
+    allocator: &mut impl FrameAllocator<Size4KiB>,
+    image: &KernelImage,
+) {
+    for segment in image.segments() {
+        let mut flags = PageTableFlags::PRESENT;
+        if segment.flags.contains(SegmentFlags::WRITE) {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !segment.flags.contains(SegmentFlags::EXECUTE) {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+
+        let aliases = [
+            segment.phys_start,
+            segment.virt_start,
+            KERNEL_VIRT_BASE + (segment.phys_start - image.phys_start),
+        ];
+        for (i, &base) in aliases.iter().enumerate() {
+            if aliases[..i].contains(&base) {
+                continue;
+            }
+            for offset in (0..segment.size).step_by(FRAME_SIZE as usize) {
+                let page = Page::<Size4KiB>::containing_address(VirtAddr::new(base + offset));
+                let frame =
+                    PhysFrame::containing_address(PhysAddr::new(segment.phys_start + offset));
+                unsafe { mapper.map_to(page, frame, flags, allocator) }
+                    .expect("Failed to map the kernel image")
+                    .ignore();
+            }
+        }
+    }
+}
+
+/// Maps every non-MMIO region of the memory map at [`PHYS_MEM_OFFSET`], using 2 MiB
+/// pages wherever the alignment allows it.
+fn map_physical_memory(
+    mapper: &mut OffsetPageTable,
+    allocator: &mut impl FrameAllocator<Size4KiB>,
+    boot_info: &BootInfo,
+) {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let huge = Size2MiB::SIZE;
+
+    for region in boot_info
+        .memory_map
+        .regions()
+        .iter()
+        .filter(|region| region.kind != MemoryRegionKind::Mmio)
+    {
+        let mut addr = region.phys_start;
+        let end = region.phys_end();
+        while addr < end {
+            let virt = VirtAddr::new(addr + PHYS_MEM_OFFSET);
+            if addr % huge == 0 && end - addr >= huge {
+                let page = Page::<Size2MiB>::containing_address(virt);
+                let frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(addr));
+                unsafe { mapper.map_to(page, frame, flags, allocator) }
+                    .expect("Failed to map physical memory")
+                    .ignore();
+                addr += huge;
+            } else {
+                let page = Page::<Size4KiB>::containing_address(virt);
+                let frame = PhysFrame::containing_address(PhysAddr::new(addr));
+                unsafe { mapper.map_to(page, frame, flags, allocator) }
+                    .expect("Failed to map physical memory")
+                    .ignore();
+                addr += FRAME_SIZE;
+            }
+        }
+    }
+}
+
+/// Whether `addr` falls in the unmapped guard page below the boot stack, which is
+/// what a kernel stack overflow touches.
+pub fn is_stack_guard(addr: VirtAddr) -> bool {
+    (KERNEL_STACK_BOTTOM..KERNEL_STACK_BOTTOM + FRAME_SIZE).contains(&addr.as_u64())
+}
+
+/// Picks up the tables built by [`init_paging`] once running on them.
+pub fn finish_paging_switch() {
+    let (pml4_frame, _) = x86_64::registers::control::Cr3::read();
+    let pml4_virt = phys_to_virt(pml4_frame.start_address());
+    let mapper = unsafe {
+        OffsetPageTable::new(
+            &mut *pml4_virt.as_mut_ptr::<PageTable>(),
+            VirtAddr::new(PHYS_MEM_OFFSET),
+        )
+    };
+    *KERNEL_PAGE_TABLE.lock() = Some(mapper);
+}
+
+fn with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> R {
+    let mut guard = KERNEL_PAGE_TABLE.lock();
+    f(guard
+        .as_mut()
+        .expect("Kernel page tables are not initialized"))
+}
+
+/// Maps a single 4 KiB page, allocating intermediate tables as needed.
+pub fn map_page(
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    with_mapper(|mapper| {
+        let mut allocator = get_and_lock_frame_allocator();
+        unsafe { mapper.map_to(page, frame, flags, &mut *allocator) }.map(|flush| flush.flush())
+    })
+}
+
+/// Unmaps a single 4 KiB page and returns the frame it pointed to.
+#[allow(dead_code)]
+pub fn unmap_page(page: Page) -> Result<PhysFrame, UnmapError> {
+    with_mapper(|mapper| {
+        mapper.unmap(page).map(|(frame, flush)| {
+            flush.flush();
+            frame
+        })
+    })
+}
+
+/// Translates a virtual address through the kernel page tables.
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    with_mapper(|mapper| mapper.translate_addr(addr))
+}
+
+/// Maps `size` bytes of device memory at `phys` and returns where it ended up.
+pub fn map_mmio(phys: PhysAddr, size: usize, cache: CacheType) -> VirtAddr {
+    let start = phys.align_down(FRAME_SIZE);
+    let end = (phys + size as u64).align_up(FRAME_SIZE);
+    let len = end - start;
+
+    let virt_start = NEXT_MMIO.fetch_add(len, Ordering::Relaxed);
+    assert!(
+        virt_start + len <= KERNEL_STACK_BOTTOM,
+        "MMIO window exhausted"
+    );
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | cache.flags();
+    for offset in (0..len).step_by(FRAME_SIZE as usize) {
+        let page = Page::containing_address(VirtAddr::new(virt_start + offset));
+        let frame = PhysFrame::containing_address(start + offset);
+        map_page(page, frame, flags).expect("Failed to map MMIO");
+    }
+
+    VirtAddr::new(virt_start + (phys - start))
+}
@@ -1,77 +1,73 @@
-use crate::{gop_render::CURSOR_STATE, serial::serial_write_str};
-use crate::gop_render::SimplifiedRenderer;
-use crate::append_number_to_string;
-use crate::get_and_lock_renderer;
-use heapless::String;
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-#[cfg(not(test))]
-#[panic_handler]
-fn panic(panic: &core::panic::PanicInfo) -> ! {
-    serial_write_str("Panic occurred: ");
-    print_panic_info_serial(&panic);
-
-    unsafe {
-        CURSOR_STATE.force_unlock(); // rare case the item is locked
-    }
-    CURSOR_STATE.lock().x = 40;
-    CURSOR_STATE.lock().y = 40;
+use x86_64::instructions::interrupts::disable;
 
-    let mut message: String<128> = String::new();
-    message
-        .push_str(panic.message().as_str().unwrap_or("unknown error"))
-        .unwrap();
+use crate::{
+    RENDERER,
+    backtrace::print_current_backtrace,
+    bk_interrupts::stop_other_cpus,
+    exceptions::{fatal_println, halt},
+    gop_render::CURSOR_STATE,
+    log_buffer::dump_log,
+    serial_println,
+};
 
-    if let Some(location) = panic.location() {
-        message.push_str(" at ").unwrap();
+/// Log records printed after the backtrace.
+const PANIC_LOG_LINES: usize = 16;
 
-        // Ensure the file name is valid UTF-8
-        if let Ok(file_name) = core::str::from_utf8(location.file().as_bytes()) {
-            message.push_str(file_name).unwrap();
-        } else {
-            message.push_str("<invalid file>").unwrap();
-        }
+/// Panics entered so far. More than one means the panic path itself panicked.
+static PANIC_DEPTH: AtomicUsize = AtomicUsize::new(0);
 
-        message.push_str(": Line ").unwrap(); // Add "Line" label
+/// Whether some CPU is handling a panic.
+pub fn is_panicking() -> bool {
+    PANIC_DEPTH.load(Ordering::SeqCst) != 0
+}
 
-        let mut line_str: String<16> = String::new();
-        append_number_to_string(&mut line_str, location.line() as usize);
-        message.push_str(&line_str).unwrap();
+/// Takes the screen over from whoever was drawing when the kernel panicked.
+///
+/// Every other CPU is stopped and the panicking code never resumes, so a held
+/// renderer or cursor lock will never be released otherwise.
+fn take_over_screen() {
+    if let Some(renderer) = RENDERER.get()
+        && renderer.is_locked()
+    {
+        unsafe { renderer.force_unlock() };
     }
-
-    get_and_lock_renderer().clear_screen();
-    get_and_lock_renderer().println(&message);
-
-    loop {
-        unsafe { core::arch::asm!("hlt") }
+    if CURSOR_STATE.is_locked() {
+        unsafe { CURSOR_STATE.force_unlock() };
     }
 }
 
-pub fn print_panic_info_serial(info: &core::panic::PanicInfo) {
-    serial_write_str("=== PANIC ===\n");
-
-    #[cfg(not(debug_assertions))]
-    serial_write_str("[WARNING] This is a release build, panic information may be limited.\n");
-
-    if let Some(location) = info.location() {
-        serial_write_str("Location: ");
-        serial_write_str(location.file());
-        serial_write_str(":");
-        serial_write_str(location.line().to_string().as_str());
-        serial_write_str(":");
-        serial_write_str(location.column().to_string().as_str());
-        serial_write_str("\n");
-    } else {
-        serial_write_str("Location: <unknown>\n");
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    disable();
+    match PANIC_DEPTH.fetch_add(1, Ordering::SeqCst) {
+        0 => {}
+        // Printing the first panic panicked, so only use the serial port this time.
+        1 => {
+            serial_println!("\nPANIC while panicking: {}", info);
+            halt()
+        }
+        _ => halt(),
     }
+    stop_other_cpus();
+    take_over_screen();
 
-    if let Some(message) = info.message().as_str() {
-        serial_write_str("Message: ");
-        serial_write_str(message);
-        serial_write_str("\n");
-    } else {
-        serial_write_str("Message: <none>\n");
+    fatal_println(format_args!("KERNEL PANIC: {}", info.message()));
+    match info.location() {
+        Some(location) => fatal_println(format_args!(
+            "  at {}:{}:{}",
+            location.file(),
+            location.line(),
+            location.column()
+        )),
+        None => fatal_println(format_args!("  at <unknown location>")),
     }
-
-    serial_write_str("=============\n");
-    serial_write_str("\n\n");
-}
\ No newline at end of file
+    print_current_backtrace();
+    dump_log(PANIC_LOG_LINES);
+    halt()
+}
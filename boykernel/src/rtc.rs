@@ -0,0 +1,225 @@
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use log::{error, info, warn};
+use x86::io::{inb, outb};
+
+use crate::{
+    acpi::{ACPI, Fadt},
+    time::{NANOS_PER_SECOND, now},
+};
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const RTC_SECONDS: u8 = 0x00;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_HOURS: u8 = 0x04;
+const RTC_DAY: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+const RTC_STATUS_A: u8 = 0x0A;
+const RTC_STATUS_B: u8 = 0x0B;
+
+/// Status A: the RTC is updating its registers, which then read as garbage.
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+/// Status B: registers hold binary values instead of BCD.
+const BINARY_MODE: u8 = 1 << 2;
+/// Status B: hours count from 0 to 23 instead of 1 to 12.
+const HOURS_24: u8 = 1 << 1;
+/// Set in the hours register for PM times in 12-hour mode.
+const HOUR_PM: u8 = 1 << 7;
+
+/// Seconds between 1970-01-01 and the moment [`now`] read zero, or 0 until
+/// [`init_rtc`] read the RTC.
+static BOOT_UNIX_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// A calendar date and time in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u32,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl DateTime {
+    /// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+    fn days_since_epoch(year: u32, month: u32, day: u32) -> u64 {
+        // Counting years from March puts the leap day at the end of the year.
+        let year = if month <= 2 { year - 1 } else { year } as u64;
+        let era = year / 400;
+        let year_of_era = year % 400;
+        let day_of_year = (153 * ((month as u64 + 9) % 12) + 2) / 5 + day as u64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        era * 146_097 + day_of_era - 719_468
+    }
+
+    pub fn to_unix(self) -> u64 {
+        Self::days_since_epoch(self.year, self.month, self.day) * 86_400
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    pub fn from_unix(timestamp: u64) -> Self {
+        let days = timestamp / 86_400 + 719_468;
+        let seconds = timestamp % 86_400;
+
+        let era = days / 146_097;
+        let day_of_era = days % 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_from_march = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+        let month = if month_from_march < 10 {
+            month_from_march + 3
+        } else {
+            month_from_march - 9
+        };
+        let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+
+        DateTime {
+            year: year as u32,
+            month: month as u32,
+            day: day as u32,
+            hour: (seconds / 3600) as u32,
+            minute: (seconds / 60 % 60) as u32,
+            second: (seconds % 60) as u32,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn read_cmos(register: u8) -> u8 {
+    unsafe {
+        outb(CMOS_ADDRESS, register);
+        inb(CMOS_DATA)
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+/// The raw time registers, read outside of an update.
+fn read_registers(century_register: u8) -> [u8; 7] {
+    while read_cmos(RTC_STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    let century = match century_register {
+        0 => 0,
+        register => read_cmos(register),
+    };
+    [
+        read_cmos(RTC_SECONDS),
+        read_cmos(RTC_MINUTES),
+        read_cmos(RTC_HOURS),
+        read_cmos(RTC_DAY),
+        read_cmos(RTC_MONTH),
+        read_cmos(RTC_YEAR),
+        century,
+    ]
+}
+
+/// Reads the date and time from the CMOS RTC, which boykernel assumes runs in UTC.
+///
+/// An update can start right after the update-in-progress check, so the registers
+/// are read until two reads in a row agree.
+pub fn read_rtc() -> DateTime {
+    let century_register = ACPI
+        .get()
+        .and_then(|acpi| acpi.fadt)
+        .map_or(0, |fadt| fadt.century_register);
+
+    let mut registers = read_registers(century_register);
+    loop {
+        let again = read_registers(century_register);
+        if again == registers {
+            break;
+        }
+        registers = again;
+    }
+
+    let status_b = read_cmos(RTC_STATUS_B);
+    let [
+        mut second,
+        mut minute,
+        hour,
+        mut day,
+        mut month,
+        mut year,
+        mut century,
+    ] = registers;
+    let pm = hour & HOUR_PM != 0;
+    let mut hour = hour & !HOUR_PM;
+    if status_b & BINARY_MODE == 0 {
+        second = from_bcd(second);
+        minute = from_bcd(minute);
+        hour = from_bcd(hour);
+        day = from_bcd(day);
+        month = from_bcd(month);
+        year = from_bcd(year);
+        century = from_bcd(century);
+    }
+    if status_b & HOURS_24 == 0 {
+        // 12 AM is midnight and 12 PM is noon.
+        hour = hour % 12 + if pm { 12 } else { 0 };
+    }
+    // Without a century register the RTC is assumed to be in this century.
+    let century = if century == 0 { 20 } else { century as u32 };
+
+    DateTime {
+        year: century * 100 + year as u32,
+        month: month as u32,
+        day: day as u32,
+        hour: hour as u32,
+        minute: minute as u32,
+        second: second as u32,
+    }
+}
+
+/// Reads the RTC once, [`wall_clock`] follows the monotonic clock from there.
+///
+/// Must run after the clock source is set up.
+pub fn init_rtc() {
+    if ACPI
+        .get()
+        .and_then(|acpi| acpi.fadt)
+        .is_some_and(|fadt| fadt.boot_arch & Fadt::NO_CMOS_RTC != 0)
+    {
+        warn!("The FADT says there is no CMOS RTC, wall clock time is unknown");
+        return;
+    }
+
+    let date = read_rtc();
+    if !(1..=12).contains(&date.month) || !(1..=31).contains(&date.day) || date.year < 1970 {
+        error!("The RTC holds a bogus date: {}", date);
+        return;
+    }
+    let boot = date.to_unix().saturating_sub(now() / NANOS_PER_SECOND);
+    BOOT_UNIX_TIME.store(boot, Ordering::Relaxed);
+    info!("RTC: {} UTC", date);
+}
+
+/// Seconds since 1970-01-01 UTC, or `None` if the RTC could not be read.
+pub fn wall_clock() -> Option<u64> {
+    match BOOT_UNIX_TIME.load(Ordering::Relaxed) {
+        0 => None,
+        boot => Some(boot + now() / NANOS_PER_SECOND),
+    }
+}
@@ -1,14 +1,360 @@
-use x86_64::instructions::port::Port;
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering},
+};
 
-const SERIAL_PORT: u16 = 0x3F8; // COM1
+use alloc::string::String;
+use boyinfo::CommandLine;
+use log::{info, warn};
+use spin::Mutex;
+use x86_64::instructions::{
+    interrupts::{self, without_interrupts},
+    port::Port,
+};
 
+use crate::{
+    ioapic::register_irq,
+    panic::is_panicking,
+    rtc::{DateTime, wall_clock},
+    time::{Uptime, now},
+};
+
+pub const COM1: u16 = 0x3F8;
+pub const COM2: u16 = 0x2F8;
+pub const COM3: u16 = 0x3E8;
+pub const COM4: u16 = 0x2E8;
+pub const DEFAULT_BAUD: u32 = 115_200;
+/// The UART clock divided by 16, which is the baud rate with a divisor of 1.
+const MAX_BAUD: u32 = 115_200;
+
+// Register offsets from the base port.
+/// Transmit/receive buffer, or the low divisor byte while DLAB is set.
+const DATA: u16 = 0;
+/// Interrupt enable, or the high divisor byte while DLAB is set.
+const INTERRUPT_ENABLE: u16 = 1;
+/// FIFO control when written, interrupt identification when read.
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_TX_EMPTY: u8 = 1 << 1;
+/// 8 data bits, no parity, 1 stop bit.
+const LCR_8N1: u8 = 0x03;
+const LCR_DLAB: u8 = 1 << 7;
+/// Enables and clears both FIFOs, with an interrupt once 14 bytes were received.
+const FCR_ENABLE: u8 = 0xC7;
+/// Set in the interrupt identification register when the FIFOs work (16550A).
+const IIR_FIFOS_ENABLED: u8 = 0b11 << 6;
+const IIR_NONE_PENDING: u8 = 1 << 0;
+const MCR_DTR_RTS: u8 = 0x03;
+/// OUT2 connects the UART interrupt to the interrupt controller on PCs.
+const MCR_OUT2: u8 = 1 << 3;
+const MCR_LOOPBACK: u8 = 1 << 4;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_TX_EMPTY: u8 = 1 << 5;
+
+const FIFO_SIZE: usize = 16;
+const RX_BUFFER_SIZE: usize = 1024;
+const TX_BUFFER_SIZE: usize = 4096;
+
+static PORT: AtomicU16 = AtomicU16::new(COM1);
+/// Set once the UART passed its loopback test.
+static PRESENT: AtomicBool = AtomicBool::new(false);
+/// Set once the UART interrupt is registered, bytes then go through the buffers.
+static INTERRUPT_DRIVEN: AtomicBool = AtomicBool::new(false);
+/// Bytes the transmitter takes at once, 1 without a working FIFO.
+static TX_BURST: AtomicUsize = AtomicUsize::new(1);
+static RX_BUFFER: Mutex<heapless::Deque<u8, RX_BUFFER_SIZE>> = Mutex::new(heapless::Deque::new());
+static TX_BUFFER: Mutex<heapless::Deque<u8, TX_BUFFER_SIZE>> = Mutex::new(heapless::Deque::new());
+
+/// Why the UART could not be set up.
+#[derive(Debug, Clone, Copy)]
+pub enum SerialError {
+    /// Not a divisor of 115200.
+    BadBaudRate(u32),
+    /// Nothing answered the loopback test at this port.
+    NoUart(u16),
+}
+
+impl fmt::Display for SerialError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SerialError::BadBaudRate(baud) => write!(
+                f,
+                "unsupported baud rate {}, it must divide {}",
+                baud, MAX_BAUD
+            ),
+            SerialError::NoUart(port) => write!(f, "no UART at port 0x{:x}", port),
+        }
+    }
+}
+
+/// A 16550 compatible UART at an I/O port base.
+#[derive(Clone, Copy)]
+struct Uart(u16);
+
+impl Uart {
+    fn read(self, register: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.0.wrapping_add(register)).read() }
+    }
+
+    fn write(self, register: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.0.wrapping_add(register)).write(value) }
+    }
+
+    fn write_polled(self, byte: u8) {
+        while self.read(LINE_STATUS) & LSR_TX_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        self.write(DATA, byte);
+    }
+}
+
+fn uart() -> Uart {
+    Uart(PORT.load(Ordering::Relaxed))
+}
+
+/// Programs the UART at `port` for `baud` 8N1 with FIFOs, and makes it the one
+/// everything is written to. Interrupts stay off until [`enable_serial_interrupts`].
+pub fn init_serial(port: u16, baud: u32) -> Result<(), SerialError> {
+    if baud == 0 || !MAX_BAUD.is_multiple_of(baud) {
+        return Err(SerialError::BadBaudRate(baud));
+    }
+    let divisor = (MAX_BAUD / baud) as u16;
+    let uart = Uart(port);
+
+    without_interrupts(|| {
+        uart.write(INTERRUPT_ENABLE, 0);
+        uart.write(LINE_CONTROL, LCR_DLAB);
+        uart.write(DATA, divisor as u8);
+        uart.write(INTERRUPT_ENABLE, (divisor >> 8) as u8);
+        uart.write(LINE_CONTROL, LCR_8N1);
+        uart.write(FIFO_CONTROL, FCR_ENABLE);
+
+        // Whatever is sent in loopback mode comes straight back if there is a UART.
+        uart.write(MODEM_CONTROL, MCR_LOOPBACK | MCR_DTR_RTS);
+        uart.write(DATA, 0xAE);
+        if uart.read(DATA) != 0xAE {
+            return Err(SerialError::NoUart(port));
+        }
+        uart.write(MODEM_CONTROL, MCR_DTR_RTS | MCR_OUT2);
+
+        let fifos = uart.read(FIFO_CONTROL) & IIR_FIFOS_ENABLED == IIR_FIFOS_ENABLED;
+        TX_BURST.store(if fifos { FIFO_SIZE } else { 1 }, Ordering::Relaxed);
+        PORT.store(port, Ordering::Relaxed);
+        PRESENT.store(true, Ordering::Relaxed);
+        Ok(())
+    })
+}
+
+/// Parses `com1` to `com4` or their base port like `0x2f8`. Other ports are
+/// refused, probing them would poke whatever device sits there (CMOS, PCI, ...).
+fn parse_port(name: &str) -> Option<u16> {
+    let port = match name {
+        "com1" => COM1,
+        "com2" => COM2,
+        "com3" => COM3,
+        "com4" => COM4,
+        _ => match name.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16).ok()?,
+            None => name.parse().ok()?,
+        },
+    };
+    [COM1, COM2, COM3, COM4].contains(&port).then_some(port)
+}
+
+/// Moves the console to `serial.port=` at `serial.baud=` from the command line.
+pub fn configure_serial(cmdline: &CommandLine) {
+    let requested_port = cmdline.value("serial.port");
+    let requested_baud = cmdline.value("serial.baud");
+    if requested_port.is_none() && requested_baud.is_none() {
+        return;
+    }
+
+    let port = match requested_port.map(|name| (name, parse_port(name))) {
+        None => PORT.load(Ordering::Relaxed),
+        Some((_, Some(port))) => port,
+        Some((name, None)) => {
+            warn!("Invalid serial.port \"{}\"", name);
+            return;
+        }
+    };
+    let baud = match requested_baud.map(|baud| (baud, baud.parse::<u32>())) {
+        None => DEFAULT_BAUD,
+        Some((_, Ok(baud))) => baud,
+        Some((baud, Err(_))) => {
+            warn!("Invalid serial.baud \"{}\"", baud);
+            return;
+        }
+    };
+    match init_serial(port, baud) {
+        Ok(()) => info!("Serial console on port 0x{:x} at {} baud", port, baud),
+        Err(err) => warn!("Keeping the serial console where it is: {}", err),
+    }
+}
+
+/// Switches the UART over to interrupt driven transmit and receive through the
+/// I/O APIC. Must run after [`crate::ioapic::init_io_apics`].
+pub fn enable_serial_interrupts() {
+    if !PRESENT.load(Ordering::Relaxed) {
+        return;
+    }
+    let port = PORT.load(Ordering::Relaxed);
+    let irq = match port {
+        COM1 | COM3 => 4,
+        COM2 | COM4 => 3,
+        _ => {
+            warn!("No known IRQ for the UART at 0x{:x}, it stays polled", port);
+            return;
+        }
+    };
+    if let Err(err) = register_irq(irq, serial_interrupt) {
+        warn!("Serial IRQ {}: {}, the UART stays polled", irq, err);
+        return;
+    }
+    without_interrupts(|| {
+        INTERRUPT_DRIVEN.store(true, Ordering::Relaxed);
+        uart().write(INTERRUPT_ENABLE, IER_RX_AVAILABLE);
+    });
+    info!(
+        "Serial port 0x{:x} is interrupt driven on IRQ {}",
+        port, irq
+    );
+}
+
+/// Hands the transmitter as much as its FIFO takes, and keeps its interrupt on for
+/// as long as there is more.
+fn fill_transmitter(uart: Uart, tx: &mut heapless::Deque<u8, TX_BUFFER_SIZE>) {
+    for _ in 0..TX_BURST.load(Ordering::Relaxed) {
+        match tx.pop_front() {
+            Some(byte) => uart.write(DATA, byte),
+            None => break,
+        }
+    }
+    let enabled = if tx.is_empty() {
+        IER_RX_AVAILABLE
+    } else {
+        IER_RX_AVAILABLE | IER_TX_EMPTY
+    };
+    uart.write(INTERRUPT_ENABLE, enabled);
+}
+
+fn serial_interrupt() {
+    let uart = uart();
+    // The IRQ is edge triggered, so everything pending must be handled before the
+    // line can fire again. The bound keeps a stuck UART from hanging the kernel.
+    for _ in 0..FIFO_SIZE {
+        if uart.read(FIFO_CONTROL) & IIR_NONE_PENDING != 0 {
+            break;
+        }
+        let mut rx = RX_BUFFER.lock();
+        while uart.read(LINE_STATUS) & LSR_DATA_READY != 0 {
+            // Input nobody reads is dropped once the buffer is full.
+            let _ = rx.push_back(uart.read(DATA));
+        }
+        drop(rx);
+        if uart.read(LINE_STATUS) & LSR_TX_EMPTY != 0 {
+            fill_transmitter(uart, &mut TX_BUFFER.lock());
+        }
+    }
+}
+
+/// Writes straight to the UART, after whatever is still buffered so the output
+/// stays in order.
+fn write_polled(byte: u8) {
+    let uart = uart();
+    without_interrupts(|| {
+        // An exception or NMI may have interrupted someone holding the buffer.
+        if let Some(mut tx) = TX_BUFFER.try_lock() {
+            while let Some(buffered) = tx.pop_front() {
+                uart.write_polled(buffered);
+            }
+        }
+        uart.write_polled(byte);
+    });
+}
+
+/// Queues `byte` for the UART interrupt, or writes it right away when interrupts
+/// are off, since nothing would send it then (e.g. when panicking).
 pub fn serial_write_byte(byte: u8) {
-    unsafe {
-        let mut line_status = Port::<u8>::new(SERIAL_PORT + 5);
-        while (line_status.read() & 0x20) == 0 {} // Wait until empty
+    if !INTERRUPT_DRIVEN.load(Ordering::Relaxed) || !interrupts::are_enabled() || is_panicking() {
+        write_polled(byte);
+        return;
+    }
+    let queued = without_interrupts(|| {
+        let mut tx = TX_BUFFER.lock();
+        if tx.push_back(byte).is_err() {
+            return false;
+        }
+        let uart = uart();
+        if uart.read(LINE_STATUS) & LSR_TX_EMPTY != 0 {
+            fill_transmitter(uart, &mut tx);
+        }
+        true
+    });
+    if !queued {
+        write_polled(byte);
+    }
+}
+
+/// The next received byte, if there is one.
+#[allow(dead_code)]
+pub fn read_byte() -> Option<u8> {
+    if INTERRUPT_DRIVEN.load(Ordering::Relaxed) {
+        return without_interrupts(|| RX_BUFFER.lock().pop_front());
+    }
+    let uart = uart();
+    (uart.read(LINE_STATUS) & LSR_DATA_READY != 0).then(|| uart.read(DATA))
+}
 
-        let mut data = Port::new(SERIAL_PORT);
-        data.write(byte);
+/// Waits for the next received byte, halting in between when interrupts can wake
+/// this up.
+fn wait_byte() -> u8 {
+    if !INTERRUPT_DRIVEN.load(Ordering::Relaxed) || !interrupts::are_enabled() {
+        loop {
+            if let Some(byte) = read_byte() {
+                return byte;
+            }
+            core::hint::spin_loop();
+        }
+    }
+    loop {
+        // Checking with interrupts off and enabling them together with `hlt` means
+        // a byte arriving in between still wakes this up.
+        interrupts::disable();
+        if let Some(byte) = RX_BUFFER.lock().pop_front() {
+            interrupts::enable();
+            return byte;
+        }
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Reads a line from the serial console, echoing it back and handling backspace.
+/// The line ending is not included.
+#[allow(dead_code)]
+pub fn read_line() -> String {
+    let mut line = String::new();
+    loop {
+        match wait_byte() {
+            b'\r' | b'\n' => {
+                serial_write_str("\r\n");
+                return line;
+            }
+            // Backspace and DEL, terminals send either.
+            0x08 | 0x7F => {
+                if line.pop().is_some() {
+                    serial_write_str("\x08 \x08");
+                }
+            }
+            byte if byte.is_ascii_graphic() || byte == b' ' => {
+                line.push(byte as char);
+                serial_write_byte(byte);
+            }
+            _ => {}
+        }
     }
 }
 
@@ -18,13 +364,47 @@ pub fn serial_write_str(s: &str) {
     }
 }
 
-pub fn info(text: &str) {
-    serial_write_str("[INFO] ");
-    serial_write_str(text);
-    serial_write_str("\n");
+/// Formats straight to the serial port, without needing the heap.
+pub struct SerialWriter;
+
+impl Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        serial_write_str(s);
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    let _ = SerialWriter.write_fmt(args);
+}
+
+/// Prints to the serial port only, like `print!`.
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => ($crate::serial::_print(format_args!($($arg)*)));
+}
+
+/// Prints a line to the serial port only, like `println!`.
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($($arg:tt)*) => ($crate::serial::_print(format_args!("{}\n", format_args!($($arg)*))));
+}
+
+/// Writes the uptime and, once the RTC has been read, the wall clock time.
+pub fn write_timestamp() {
+    let _ = write!(SerialWriter, "[{}", Uptime(now()));
+    if let Some(timestamp) = wall_clock() {
+        let _ = write!(SerialWriter, " {}", DateTime::from_unix(timestamp));
+    }
+    serial_write_str("] ");
 }
 
+/// Writes an error line straight to the port, for code that must not go through the
+/// logger because it may have been interrupted holding one of its locks.
 pub fn error(text: &str) {
+    write_timestamp();
     serial_write_str("[ERROR] ");
     serial_write_str(text);
     serial_write_str("\n");
@@ -0,0 +1,309 @@
+use core::{
+    arch::x86_64::__cpuid,
+    fmt,
+    sync::atomic::{AtomicU8, AtomicU64, Ordering},
+};
+
+use log::{error, info, warn};
+use x86::io::{inb, outb};
+
+use crate::{
+    bk_interrupts::{read_apic, write_apic},
+    boot_info::BOOT_INFO,
+    hpet::hpet,
+    utils::read_timestamp_counter,
+};
+
+pub const NANOS_PER_SECOND: u64 = 1_000_000_000;
+/// Timer interrupts per second unless `timer.hz=N` is on the command line.
+const DEFAULT_TICK_HZ: u64 = 100;
+pub const TIMER_VECTOR: u8 = 32;
+
+const PIT_HZ: u64 = 1_193_182;
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+const PIT_GATE_PORT: u16 = 0x61;
+/// Length of the PIT window everything is calibrated against. Longer is more
+/// precise but slows down the boot, the count must fit in 16 bits.
+const CALIBRATION_MS: u64 = 50;
+/// The HPET is precise enough for a shorter window.
+const HPET_CALIBRATION_MS: u64 = 10;
+
+const APIC_LVT_TIMER: isize = 0x320;
+const APIC_TIMER_INITIAL: isize = 0x380;
+const APIC_TIMER_CURRENT: isize = 0x390;
+const APIC_TIMER_DIVIDE: isize = 0x3E0;
+/// Divide configuration value for dividing the bus clock by 16.
+const APIC_DIVIDE_16: u32 = 0b0011;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_PERIODIC: u32 = 1 << 17;
+
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+static CLOCK_SOURCE: AtomicU8 = AtomicU8::new(ClockSource::Tsc as u8);
+/// Counter value of the clock source when [`init_time`] ran.
+static BOOT_COUNT: AtomicU64 = AtomicU64::new(0);
+/// APIC timer counts per second, with the divider set to 16.
+static APIC_TIMER_HZ: AtomicU64 = AtomicU64::new(0);
+static TICK_HZ: AtomicU64 = AtomicU64::new(DEFAULT_TICK_HZ);
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// A tick rate the APIC timer cannot run at.
+#[derive(Debug, Clone, Copy)]
+pub struct TickRateError {
+    pub hz: u64,
+    /// The APIC timer frequency, the fastest it can tick.
+    pub max: u64,
+}
+
+impl fmt::Display for TickRateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "the timer cannot tick at {} Hz, it must be 1 to {}",
+            self.hz, self.max
+        )
+    }
+}
+
+/// A free-running counter [`now`] is derived from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ClockSource {
+    /// The time stamp counter, calibrated at boot. The cheapest to read.
+    Tsc,
+    /// The HPET main counter, only used when it is 64 bits wide.
+    Hpet,
+}
+
+impl ClockSource {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "tsc" => Some(ClockSource::Tsc),
+            "hpet" => Some(ClockSource::Hpet),
+            _ => None,
+        }
+    }
+
+    fn is_available(self) -> bool {
+        match self {
+            ClockSource::Tsc => TSC_HZ.load(Ordering::Relaxed) != 0,
+            ClockSource::Hpet => hpet().is_some_and(|hpet| hpet.has_64bit_counter()),
+        }
+    }
+
+    fn counter(self) -> u64 {
+        match self {
+            ClockSource::Tsc => read_timestamp_counter(),
+            ClockSource::Hpet => hpet().map_or(0, |hpet| hpet.counter()),
+        }
+    }
+
+    /// Counter increments per second.
+    fn frequency(self) -> u64 {
+        match self {
+            ClockSource::Tsc => TSC_HZ.load(Ordering::Relaxed),
+            ClockSource::Hpet => hpet().map_or(0, |hpet| hpet.frequency()),
+        }
+    }
+}
+
+/// The clock source [`now`] reads.
+pub fn clock_source() -> ClockSource {
+    match CLOCK_SOURCE.load(Ordering::Relaxed) {
+        1 => ClockSource::Hpet,
+        _ => ClockSource::Tsc,
+    }
+}
+
+/// Picks the clock source: the one named by `clocksource=` on the command line if
+/// it works, otherwise an invariant TSC, then a 64-bit HPET, then whatever TSC
+/// there is.
+fn choose_clock_source() -> ClockSource {
+    let requested = BOOT_INFO
+        .get()
+        .and_then(|boot_info| boot_info.cmdline.value("clocksource"));
+    if let Some(name) = requested {
+        match ClockSource::from_name(name) {
+            Some(source) if source.is_available() => return source,
+            Some(_) => warn!("Clock source \"{}\" is not available", name),
+            None => warn!("Unknown clock source \"{}\"", name),
+        }
+    }
+
+    if tsc_is_invariant() {
+        ClockSource::Tsc
+    } else if ClockSource::Hpet.is_available() {
+        ClockSource::Hpet
+    } else {
+        info!("The TSC is not invariant, time may drift if the CPU changes frequency");
+        ClockSource::Tsc
+    }
+}
+
+/// Busy-waits `ms` milliseconds on PIT channel 2, with the speaker kept off.
+fn pit_wait(ms: u64) {
+    let count = PIT_HZ * ms / 1000;
+    unsafe {
+        // Raise the channel 2 gate with the speaker disconnected.
+        let gate = inb(PIT_GATE_PORT);
+        outb(PIT_GATE_PORT, (gate & !0x02) | 0x01);
+        // Channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count).
+        outb(PIT_COMMAND, 0xB0);
+        outb(PIT_CHANNEL2, count as u8);
+        outb(PIT_CHANNEL2, (count >> 8) as u8);
+        // OUT2 goes high once the count reaches zero.
+        while inb(PIT_GATE_PORT) & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        outb(PIT_GATE_PORT, gate);
+    }
+}
+
+/// Measures the TSC and APIC timer frequencies against the HPET, or the PIT when
+/// there is no HPET.
+fn calibrate() -> (u64, u64) {
+    let window_ms = match hpet() {
+        Some(_) => HPET_CALIBRATION_MS,
+        None => CALIBRATION_MS,
+    };
+
+    write_apic(APIC_TIMER_DIVIDE, APIC_DIVIDE_16);
+    write_apic(APIC_LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+    write_apic(APIC_TIMER_INITIAL, u32::MAX);
+    let tsc_start = read_timestamp_counter();
+
+    match hpet() {
+        Some(hpet) => hpet.wait_ms(window_ms),
+        None => pit_wait(window_ms),
+    }
+
+    let tsc_end = read_timestamp_counter();
+    let apic_elapsed = u32::MAX - read_apic(APIC_TIMER_CURRENT);
+    write_apic(APIC_TIMER_INITIAL, 0);
+
+    let tsc_hz = (tsc_end - tsc_start) * 1000 / window_ms;
+    let apic_hz = apic_elapsed as u64 * 1000 / window_ms;
+    (tsc_hz, apic_hz)
+}
+
+/// Whether the TSC runs at the same rate in every P-, C- and T-state.
+fn tsc_is_invariant() -> bool {
+    // CPUID.80000007H:EDX.InvariantTSC[bit 8]
+    __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0
+}
+
+/// Calibrates the TSC and the APIC timer, picks a clock source, then starts the
+/// periodic timer interrupt.
+///
+/// Must run once the local APIC is mapped and the HPET is set up, and before
+/// anything calls [`now`].
+pub fn init_time() {
+    let (tsc_hz, apic_hz) = calibrate();
+    TSC_HZ.store(tsc_hz, Ordering::Relaxed);
+    APIC_TIMER_HZ.store(apic_hz, Ordering::Relaxed);
+    info!(
+        "TSC: {}.{:03} MHz, APIC timer: {}.{:03} MHz",
+        tsc_hz / 1_000_000,
+        tsc_hz / 1000 % 1000,
+        apic_hz / 1_000_000,
+        apic_hz / 1000 % 1000
+    );
+
+    let source = choose_clock_source();
+    BOOT_COUNT.store(source.counter(), Ordering::Relaxed);
+    CLOCK_SOURCE.store(source as u8, Ordering::Relaxed);
+    info!("Clock source: {:?} at {} Hz", source, source.frequency());
+
+    let requested = BOOT_INFO
+        .get()
+        .and_then(|boot_info| boot_info.cmdline.value("timer.hz"));
+    let tick_hz = match requested.map(str::parse::<u64>) {
+        None => DEFAULT_TICK_HZ,
+        Some(Ok(hz)) => hz,
+        Some(Err(_)) => {
+            warn!(
+                "Invalid timer.hz \"{}\", using {}",
+                requested.unwrap_or(""),
+                DEFAULT_TICK_HZ
+            );
+            DEFAULT_TICK_HZ
+        }
+    };
+    if tick_hz != DEFAULT_TICK_HZ {
+        match set_tick_rate(tick_hz) {
+            Ok(()) => return,
+            Err(err) => warn!("Invalid timer.hz: {}, using {}", err, DEFAULT_TICK_HZ),
+        }
+    }
+    // Only fails when calibration found no APIC timer frequency.
+    if let Err(err) = set_tick_rate(DEFAULT_TICK_HZ) {
+        error!("No timer interrupt: {}", err);
+    }
+}
+
+/// Reprograms the periodic timer interrupt to fire `hz` times per second, at most
+/// once per APIC timer count.
+pub fn set_tick_rate(hz: u64) -> Result<(), TickRateError> {
+    let apic_hz = APIC_TIMER_HZ.load(Ordering::Relaxed);
+    if !(1..=apic_hz).contains(&hz) {
+        return Err(TickRateError { hz, max: apic_hz });
+    }
+    let count = (apic_hz / hz).min(u32::MAX as u64);
+    TICK_HZ.store(hz, Ordering::Relaxed);
+    write_apic(APIC_TIMER_DIVIDE, APIC_DIVIDE_16);
+    write_apic(APIC_LVT_TIMER, LVT_PERIODIC | TIMER_VECTOR as u32);
+    write_apic(APIC_TIMER_INITIAL, count as u32);
+    info!("Timer interrupt at {} Hz", hz);
+    Ok(())
+}
+
+/// Formats nanoseconds since boot as seconds with microseconds, like `    1.234567`.
+pub struct Uptime(pub u64);
+
+impl fmt::Display for Uptime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:5}.{:06}",
+            self.0 / NANOS_PER_SECOND,
+            self.0 % NANOS_PER_SECOND / 1000
+        )
+    }
+}
+
+/// Nanoseconds since [`init_time`], never going backwards.
+pub fn now() -> u64 {
+    let source = clock_source();
+    let hz = source.frequency();
+    if hz == 0 {
+        return 0;
+    }
+    let elapsed = source
+        .counter()
+        .saturating_sub(BOOT_COUNT.load(Ordering::Relaxed));
+    (elapsed as u128 * NANOS_PER_SECOND as u128 / hz as u128) as u64
+}
+
+/// Timer interrupts since [`init_time`].
+#[allow(dead_code)]
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+#[allow(dead_code)]
+pub fn tick_rate() -> u64 {
+    TICK_HZ.load(Ordering::Relaxed)
+}
+
+/// Called from the timer interrupt handler.
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Busy-waits for `milliseconds`.
+pub fn sleep(milliseconds: u64) {
+    let deadline = now() + milliseconds * 1_000_000;
+    while now() < deadline {
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,223 @@
+use boyinfo::{
+    BOOT_INFO_MAGIC, BOOT_INFO_VERSION, BootInfo, CommandLine, FramebufferInfo, KernelImage,
+    KernelSymbol, KernelSymbols, MemoryMap, MemoryRegion, MemoryRegionKind,
+};
+use log::{info, warn};
+use uefi::{
+    boot::{self, AllocateType, MemoryType},
+    mem::memory_map::MemoryMap as _,
+    table::cfg::{ACPI_GUID, ACPI2_GUID, SMBIOS_GUID, SMBIOS3_GUID},
+};
+
+use crate::symbols::Symbol;
+
+/// Extra memory map slots on top of the current map, since allocating the boot info
+/// and exiting boot services can both split existing descriptors.
+const MEMORY_MAP_SLACK: usize = 64;
+
+/// Allocates `count` values of `T` in LOADER_DATA pages, which the kernel keeps around.
+fn allocate_handoff<T>(count: usize) -> *mut T {
+    let bytes = core::mem::size_of::<T>() * count.max(1);
+    let num_pages = bytes.div_ceil(0x1000);
+    boot::allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, num_pages)
+        .expect("Failed to allocate boot info pages")
+        .as_ptr()
+        .cast()
+}
+
+/// Entry points the firmware publishes in the system table's configuration tables.
+struct FirmwareTables {
+    rsdp: u64,
+    smbios: u64,
+}
+
+/// Looks up the ACPI RSDP and the SMBIOS entry point, preferring the ACPI 2.0 and
+/// SMBIOS 3 ones and falling back to the older ones with a warning.
+fn find_firmware_tables() -> FirmwareTables {
+    uefi::system::with_config_table(|entries| {
+        let find = |guid| {
+            entries
+                .iter()
+                .find(|entry| entry.guid == guid)
+                .map(|entry| entry.address as u64)
+        };
+
+        let rsdp = find(ACPI2_GUID).unwrap_or_else(|| match find(ACPI_GUID) {
+            Some(address) => {
+                warn!("No ACPI 2.0 RSDP, using the ACPI 1.0 one without an XSDT");
+                address
+            }
+            None => {
+                warn!("The firmware has no ACPI tables");
+                0
+            }
+        });
+        let smbios = find(SMBIOS3_GUID).unwrap_or_else(|| match find(SMBIOS_GUID) {
+            Some(address) => {
+                warn!("No SMBIOS 3 entry point, using the 32-bit one");
+                address
+            }
+            None => {
+                warn!("The firmware has no SMBIOS tables");
+                0
+            }
+        });
+        info!(
+            "ACPI RSDP at 0x{:x}, SMBIOS entry point at 0x{:x}",
+            rsdp, smbios
+        );
+
+        FirmwareTables { rsdp, smbios }
+    })
+}
+
+fn copy_cmdline(cmdline: &str) -> CommandLine {
+    let dest = allocate_handoff::<u8>(cmdline.len());
+    unsafe { core::ptr::copy_nonoverlapping(cmdline.as_ptr(), dest, cmdline.len()) };
+    CommandLine {
+        address: dest as u64,
+        len: cmdline.len() as u64,
+    }
+}
+
+/// Copies the symbol table and all names into memory the kernel keeps.
+fn copy_symbols(symbols: &[Symbol]) -> KernelSymbols {
+    if symbols.is_empty() {
+        return KernelSymbols {
+            symbols: 0,
+            count: 0,
+            names: 0,
+            names_len: 0,
+        };
+    }
+
+    let names_len: usize = symbols.iter().map(|symbol| symbol.name.len()).sum();
+    let table = allocate_handoff::<KernelSymbol>(symbols.len());
+    let names = allocate_handoff::<u8>(names_len);
+    let mut name_offset = 0;
+    for (i, symbol) in symbols.iter().enumerate() {
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                symbol.name.as_ptr(),
+                names.add(name_offset),
+                symbol.name.len(),
+            );
+            table.add(i).write(KernelSymbol {
+                address: symbol.address,
+                size: symbol.size,
+                name_offset: name_offset as u32,
+                name_len: symbol.name.len() as u32,
+            });
+        }
+        name_offset += symbol.name.len();
+    }
+
+    KernelSymbols {
+        symbols: table as u64,
+        count: symbols.len() as u64,
+        names: names as u64,
+        names_len: names_len as u64,
+    }
+}
+
+fn region_kind(ty: MemoryType) -> MemoryRegionKind {
+    match ty {
+        MemoryType::CONVENTIONAL => MemoryRegionKind::Usable,
+        MemoryType::BOOT_SERVICES_CODE | MemoryType::BOOT_SERVICES_DATA => {
+            MemoryRegionKind::BootServices
+        }
+        MemoryType::LOADER_CODE => MemoryRegionKind::LoaderCode,
+        MemoryType::LOADER_DATA => MemoryRegionKind::LoaderData,
+        MemoryType::ACPI_RECLAIM => MemoryRegionKind::AcpiReclaimable,
+        MemoryType::MMIO | MemoryType::MMIO_PORT_SPACE => MemoryRegionKind::Mmio,
+        _ => MemoryRegionKind::Reserved,
+    }
+}
+
+/// Boot info that is complete except for the final memory map, which only exists
+/// once boot services are gone.
+pub struct PendingBootInfo {
+    boot_info: &'static mut BootInfo,
+    regions: *mut MemoryRegion,
+    capacity: usize,
+}
+
+impl PendingBootInfo {
+    /// Exits UEFI boot services and fills in the memory map from the final descriptors.
+    ///
+    /// Nothing that relies on boot services (logging, allocation, protocols) may be
+    /// used after this, so all protocols must already be closed.
+    pub fn exit_boot_services(self) -> &'static mut BootInfo {
+        let memory_map = unsafe { boot::exit_boot_services(MemoryType::LOADER_DATA) };
+
+        let mut len = 0;
+        let mut entries = memory_map.entries();
+        for desc in entries.by_ref().take(self.capacity) {
+            unsafe {
+                self.regions.add(len).write(MemoryRegion {
+                    kind: region_kind(desc.ty),
+                    firmware_type: desc.ty.0,
+                    phys_start: desc.phys_start,
+                    page_count: desc.page_count,
+                    attributes: desc.att.bits(),
+                });
+            }
+            len += 1;
+        }
+
+        // Logging is gone by now, the kernel reports this instead.
+        let dropped = entries.count();
+
+        self.boot_info.memory_map = MemoryMap {
+            regions: self.regions as u64,
+            len: len as u64,
+            dropped: dropped as u64,
+        };
+        self.boot_info
+    }
+}
+
+/// Builds the [`BootInfo`] for the kernel in memory that survives the jump.
+pub fn build_boot_info(
+    framebuffer: FramebufferInfo,
+    kernel: KernelImage,
+    symbols: &[Symbol],
+    cmdline: &str,
+) -> PendingBootInfo {
+    let boot_info = allocate_handoff::<BootInfo>(1);
+    let cmdline = copy_cmdline(cmdline);
+    let symbols = copy_symbols(symbols);
+    let tables = find_firmware_tables();
+
+    let current_len = boot::memory_map(MemoryType::LOADER_DATA)
+        .expect("Failed to get memory map")
+        .entries()
+        .count();
+    let capacity = current_len + MEMORY_MAP_SLACK;
+    let regions = allocate_handoff::<MemoryRegion>(capacity);
+
+    unsafe {
+        boot_info.write(BootInfo {
+            magic: BOOT_INFO_MAGIC,
+            version: BOOT_INFO_VERSION,
+            size: core::mem::size_of::<BootInfo>() as u32,
+            framebuffer,
+            memory_map: MemoryMap {
+                regions: 0,
+                len: 0,
+                dropped: 0,
+            },
+            rsdp_address: tables.rsdp,
+            smbios_address: tables.smbios,
+            kernel,
+            symbols,
+            cmdline,
+        });
+
+        PendingBootInfo {
+            boot_info: &mut *boot_info,
+            regions,
+            capacity,
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use log::{LevelFilter, info, warn};
+use uefi::CString16;
+
+use crate::files::read_file;
+
+pub const CONFIG_PATH: &str = "\\EFI\\BOOT\\boy.cfg";
+pub const DEFAULT_KERNEL_PATH: &str = "\\EFI\\BOOT\\boykernel";
+
+/// A kernel that can be booted, from a `[name]` section of the config.
+#[derive(Debug, Clone)]
+pub struct BootEntry {
+    pub name: String,
+    pub kernel: String,
+    pub cmdline: String,
+}
+
+impl BootEntry {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            kernel: DEFAULT_KERNEL_PATH.to_string(),
+            cmdline: String::new(),
+        }
+    }
+}
+
+/// Settings read from [`CONFIG_PATH`].
+///
+/// ```text
+/// # Keys before the first section apply to the whole loader.
+/// timeout = 3
+/// resolution = 1280x720
+/// log_level = info
+/// default = release
+/// fallback = stable
+/// kaslr = true
+///
+/// [release]
+/// kernel = \EFI\BOOT\boykernel
+/// cmdline = acpi.dump
+/// ```
+///
+/// `kernel` and `cmdline` before the first section describe an entry named `default`.
+#[derive(Debug)]
+pub struct BootConfig {
+    /// Seconds to wait before booting the default entry.
+    pub timeout: u64,
+    /// Preferred GOP resolution as (width, height).
+    pub resolution: Option<(usize, usize)>,
+    pub log_level: LevelFilter,
+    pub kaslr: bool,
+    /// Index into `entries`.
+    pub default_entry: usize,
+    /// Entry booted instead if the selected kernel fails to load, index into `entries`.
+    pub fallback_entry: Option<usize>,
+    /// Never empty.
+    pub entries: Vec<BootEntry>,
+}
+
+impl Default for BootConfig {
+    fn default() -> Self {
+        Self {
+            timeout: 0,
+            resolution: None,
+            log_level: LevelFilter::Info,
+            kaslr: true,
+            default_entry: 0,
+            fallback_entry: None,
+            entries: Vec::from([BootEntry::new("default")]),
+        }
+    }
+}
+
+impl BootConfig {
+    pub fn default_entry(&self) -> &BootEntry {
+        &self.entries[self.default_entry]
+    }
+}
+
+fn parse_resolution(value: &str) -> Option<(usize, usize)> {
+    let (width, height) = value.split_once('x')?;
+    Some((width.trim().parse().ok()?, height.trim().parse().ok()?))
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "true" | "yes" | "on" | "1" => Some(true),
+        "false" | "no" | "off" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parses the config text. Mistakes are logged as warnings and skipped, a broken
+/// config must never keep the machine from booting.
+pub fn parse_config(text: &str) -> BootConfig {
+    let mut config = BootConfig::default();
+    let mut global = BootEntry::new("default");
+    let mut global_used = false;
+    let mut entries: Vec<BootEntry> = Vec::new();
+    let mut default_name = None;
+    let mut fallback_name = None;
+
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(section) = line.strip_prefix('[') {
+            let Some(name) = section.strip_suffix(']').map(str::trim) else {
+                warn!("boy.cfg:{}: unterminated section header", line_number);
+                continue;
+            };
+            if entries.iter().any(|entry| entry.name == name) {
+                warn!("boy.cfg:{}: duplicate entry \"{}\"", line_number, name);
+            }
+            entries.push(BootEntry::new(name));
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            warn!("boy.cfg:{}: expected `key = value`", line_number);
+            continue;
+        };
+        let (key, value) = (key.trim(), value.trim());
+
+        let in_section = !entries.is_empty();
+        let entry = match entries.last_mut() {
+            Some(entry) => entry,
+            None => &mut global,
+        };
+
+        match key {
+            // Paths go to the firmware as UCS-2, which not every string fits.
+            "kernel" if CString16::try_from(value).is_err() => {
+                warn!(
+                    "boy.cfg:{}: kernel path \"{}\" is not valid UCS-2",
+                    line_number, value
+                );
+            }
+            "kernel" => {
+                entry.kernel = value.to_string();
+                global_used |= !in_section;
+            }
+            "cmdline" => {
+                entry.cmdline = value.to_string();
+                global_used |= !in_section;
+            }
+            "timeout" | "resolution" | "log_level" | "default" | "fallback" | "kaslr"
+                if in_section =>
+            {
+                warn!(
+                    "boy.cfg:{}: `{}` must come before the first section",
+                    line_number, key
+                );
+            }
+            "timeout" => match value.parse() {
+                Ok(seconds) => config.timeout = seconds,
+                Err(_) => warn!("boy.cfg:{}: invalid timeout \"{}\"", line_number, value),
+            },
+            "resolution" => match parse_resolution(value) {
+                Some(resolution) => config.resolution = Some(resolution),
+                None => warn!(
+                    "boy.cfg:{}: invalid resolution \"{}\", expected WIDTHxHEIGHT",
+                    line_number, value
+                ),
+            },
+            "log_level" => match value.parse() {
+                Ok(level) => config.log_level = level,
+                Err(_) => warn!("boy.cfg:{}: invalid log level \"{}\"", line_number, value),
+            },
+            "default" => default_name = Some(value.to_string()),
+            "fallback" => fallback_name = Some(value.to_string()),
+            "kaslr" => match parse_bool(value) {
+                Some(kaslr) => config.kaslr = kaslr,
+                None => warn!("boy.cfg:{}: invalid boolean \"{}\"", line_number, value),
+            },
+            _ => warn!("boy.cfg:{}: unknown key \"{}\"", line_number, key),
+        }
+    }
+
+    if global_used || entries.is_empty() {
+        entries.insert(0, global);
+    }
+    config.entries = entries;
+
+    let find_entry = |name: String, what: &str| {
+        let index = config.entries.iter().position(|entry| entry.name == name);
+        if index.is_none() {
+            warn!("boy.cfg: {} entry \"{}\" does not exist", what, name);
+        }
+        index
+    };
+    let default_entry = default_name.and_then(|name| find_entry(name, "default"));
+    let fallback_entry = fallback_name.and_then(|name| find_entry(name, "fallback"));
+    config.default_entry = default_entry.unwrap_or(0);
+    config.fallback_entry = fallback_entry;
+
+    config
+}
+
+/// Reads and parses [`CONFIG_PATH`], falling back to the defaults if it is missing.
+pub fn load_config() -> BootConfig {
+    let bytes = match read_file(CONFIG_PATH) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            info!("No boot config ({}), using defaults", err);
+            return BootConfig::default();
+        }
+    };
+    match core::str::from_utf8(&bytes) {
+        Ok(text) => parse_config(text),
+        Err(_) => {
+            warn!("boy.cfg is not valid UTF-8, using defaults");
+            BootConfig::default()
+        }
+    }
+}
@@ -1,61 +1,428 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+use boyinfo::{KernelEntry, KernelImage, KernelSegment, MAX_KERNEL_SEGMENTS, SegmentFlags};
 use uefi::boot::{self, AllocateType, MemoryType};
-use xmas_elf::{ElfFile, program};
+use xmas_elf::{
+    ElfFile,
+    dynamic::Tag,
+    header::{Class, Machine, Type},
+    program::{self, SegmentData},
+    sections::Rela,
+};
 
-use crate::files::read_file;
+use crate::{
+    files::read_file,
+    kaslr::pick_load_address,
+    symbols::{Symbol, read_symbols},
+};
 
-const KERNEL_LOAD_BASE: u64 = 0x1_0000_0000; // 4GB
+const PAGE_SIZE: u64 = 0x1000;
 
-pub fn load_kernel(_file_path: &str) -> (usize, unsafe extern "C" fn() -> !) {
-    let bytes = read_file("\\EFI\\BOOT\\boykernel").unwrap();
-    let elf = ElfFile::new(&bytes).expect("Failed to parse ELF file");
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8;
 
-    for ph in elf.program_iter() {
-        if ph.get_type().unwrap() == program::Type::Dynamic {
-            log::warn!("Skipping dynamic segment");
+/// Result of loading the kernel ELF into memory.
+pub struct LoadedKernel {
+    /// Physical address the kernel is entered at.
+    pub entry_point: u64,
+    pub entry: KernelEntry,
+    pub image: KernelImage,
+    /// Function symbols for the kernel's backtraces.
+    pub symbols: Vec<Symbol>,
+}
+
+/// Why the kernel could not be loaded.
+#[derive(Debug)]
+pub enum LoadError {
+    Read(uefi::fs::Error),
+    Parse(&'static str),
+    WrongClass(Class),
+    WrongMachine(Machine),
+    WrongType(Type),
+    /// A relocation other than `R_X86_64_RELATIVE` in a PIE kernel.
+    UnsupportedRelocation {
+        kind: u32,
+        offset: u64,
+    },
+    NoSegments,
+    TooManySegments,
+    /// A segment whose virtual and physical addresses differ in their page offset.
+    MisalignedSegment {
+        virt: u64,
+        phys: u64,
+    },
+    /// Two segments touch the same page, so their permissions can't be told apart.
+    OverlappingSegments {
+        virt: u64,
+    },
+    EntryOutsideImage(u64),
+    /// Not enough free memory for the image, at `phys` if it has to go there.
+    Allocation {
+        phys: Option<u64>,
+        pages: usize,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Read(err) => write!(f, "could not read the kernel: {}", err),
+            LoadError::Parse(err) => write!(f, "invalid ELF file: {}", err),
+            LoadError::WrongClass(class) => write!(f, "expected a 64-bit ELF, found {:?}", class),
+            LoadError::WrongMachine(machine) => {
+                write!(f, "expected an x86_64 ELF, found {:?}", machine)
+            }
+            LoadError::WrongType(ty) => write!(
+                f,
+                "expected an executable or position independent ELF, found {:?}",
+                ty
+            ),
+            LoadError::UnsupportedRelocation { kind, offset } => write!(
+                f,
+                "unsupported relocation {} ({}) at 0x{:x}, only R_X86_64_RELATIVE is handled",
+                relocation_name(*kind),
+                kind,
+                offset
+            ),
+            LoadError::NoSegments => write!(f, "the kernel has no loadable segments"),
+            LoadError::TooManySegments => write!(
+                f,
+                "the kernel has more than {} loadable segments",
+                MAX_KERNEL_SEGMENTS
+            ),
+            LoadError::MisalignedSegment { virt, phys } => write!(
+                f,
+                "segment at 0x{:x} is loaded at 0x{:x}, which is not the same page offset",
+                virt, phys
+            ),
+            LoadError::OverlappingSegments { virt } => {
+                write!(f, "segments share the page at 0x{:x}", virt)
+            }
+            LoadError::EntryOutsideImage(entry) => {
+                write!(f, "entry point 0x{:x} is not inside any segment", entry)
+            }
+            LoadError::Allocation {
+                phys: Some(phys),
+                pages,
+            } => write!(
+                f,
+                "could not allocate {} pages at 0x{:x}, which the firmware already uses",
+                pages, phys
+            ),
+            LoadError::Allocation { phys: None, pages } => {
+                write!(f, "could not allocate {} pages for the kernel", pages)
+            }
         }
-        if ph.get_type().expect("Failed to get header type") != program::Type::Load {
+    }
+}
+
+fn align_down(value: u64) -> u64 {
+    value & !(PAGE_SIZE - 1)
+}
+
+fn align_up(value: u64) -> u64 {
+    align_down(value + PAGE_SIZE - 1)
+}
+
+/// Returns whether the kernel is position independent (`ET_DYN`).
+fn check_header(elf: &ElfFile) -> Result<bool, LoadError> {
+    let class = elf.header.pt1.class();
+    if class != Class::SixtyFour {
+        return Err(LoadError::WrongClass(class));
+    }
+    let machine = elf.header.pt2.machine().as_machine();
+    if machine != Machine::X86_64 {
+        return Err(LoadError::WrongMachine(machine));
+    }
+    match elf.header.pt2.type_().as_type() {
+        Type::Executable => Ok(false),
+        Type::SharedObject => Ok(true),
+        ty => Err(LoadError::WrongType(ty)),
+    }
+}
+
+fn relocation_name(kind: u32) -> &'static str {
+    match kind {
+        1 => "R_X86_64_64",
+        2 => "R_X86_64_PC32",
+        5 => "R_X86_64_COPY",
+        6 => "R_X86_64_GLOB_DAT",
+        7 => "R_X86_64_JUMP_SLOT",
+        8 => "R_X86_64_RELATIVE",
+        16 => "R_X86_64_DTPMOD64",
+        18 => "R_X86_64_TPOFF64",
+        37 => "R_X86_64_IRELATIVE",
+        _ => "unknown",
+    }
+}
+
+/// Loads the kernel ELF at `file_path` and returns where it ended up.
+///
+/// Every `PT_LOAD` segment is described in the returned [`KernelImage`] with its
+/// R/W/X flags, so the kernel can map itself with the right permissions. Executables
+/// are copied to the `p_paddr` of each segment. Position independent kernels go
+/// wherever the firmware has room and get their `R_X86_64_RELATIVE` relocations
+/// applied for that address, which is random if `randomize` is set. Either way the
+/// kernel is entered on the firmware's identity map, at the physical address of its
+/// entry point.
+pub fn load_kernel(file_path: &str, randomize: bool) -> Result<LoadedKernel, LoadError> {
+    let bytes = read_file(file_path).map_err(LoadError::Read)?;
+    let elf = ElfFile::new(&bytes).map_err(LoadError::Parse)?;
+    let pie = check_header(&elf)?;
+
+    let mut image = KernelImage {
+        phys_start: u64::MAX,
+        phys_end: 0,
+        virt_start: u64::MAX,
+        virt_end: 0,
+        slide: 0,
+        randomized: 0,
+        segment_count: 0,
+        segments: [KernelSegment::EMPTY; MAX_KERNEL_SEGMENTS],
+    };
+
+    for ph in elf.program_iter() {
+        if ph.get_type().map_err(LoadError::Parse)? != program::Type::Load || ph.mem_size() == 0 {
             continue;
         }
 
-        let file_offset = ph.offset() as usize;
-        let file_size = ph.file_size() as usize;
-        let mem_size = ph.mem_size() as usize;
-        let virt_addr = ph.virtual_addr() as usize;
+        if ph.file_size() > ph.mem_size() {
+            return Err(LoadError::Parse(
+                "segment is larger in the file than in memory",
+            ));
+        }
 
-        let aligned_virt_addr = virt_addr & !0xFFF;
-        let page_offset = virt_addr - aligned_virt_addr;
-        let total_size = page_offset + mem_size;
-        let num_pages = (total_size + 0xFFF) / 0x1000;
+        // The physical address of a PIE segment means nothing, it moves with the image.
+        let virt = ph.virtual_addr();
+        let phys = if pie { virt } else { ph.physical_addr() };
+        if virt % PAGE_SIZE != phys % PAGE_SIZE {
+            return Err(LoadError::MisalignedSegment { virt, phys });
+        }
 
-        let mem_type = if ph.flags().is_execute() {
-            MemoryType::LOADER_CODE
-        } else {
-            MemoryType::LOADER_DATA
+        let count = image.segment_count as usize;
+        if count == MAX_KERNEL_SEGMENTS {
+            return Err(LoadError::TooManySegments);
+        }
+
+        let virt_start = align_down(virt);
+        let virt_end = align_up(virt + ph.mem_size());
+        if let Some(other) = image
+            .segments()
+            .iter()
+            .find(|other| virt_start < other.virt_start + other.size && other.virt_start < virt_end)
+        {
+            return Err(LoadError::OverlappingSegments {
+                virt: virt_start.max(other.virt_start),
+            });
+        }
+
+        image.segments[count] = KernelSegment {
+            phys_start: align_down(phys),
+            virt_start,
+            size: virt_end - virt_start,
+            flags: SegmentFlags(ph.flags().0 & 0b111),
         };
+        image.segment_count += 1;
 
-        let dest_ptr = boot::allocate_pages(
-            AllocateType::Address(KERNEL_LOAD_BASE + u64::try_from(aligned_virt_addr).unwrap()),
-            mem_type,
-            num_pages,
-        )
-        .expect("Failed to allocate pages")
-        .as_ptr();
+        image.phys_start = image.phys_start.min(align_down(phys));
+        image.phys_end = image.phys_end.max(align_up(phys + ph.mem_size()));
+        image.virt_start = image.virt_start.min(virt_start);
+        image.virt_end = image.virt_end.max(virt_end);
+    }
+
+    if image.segment_count == 0 {
+        return Err(LoadError::NoSegments);
+    }
+
+    // One allocation for the whole image, zeroed so .bss and the gaps between
+    // segments start out clean.
+    let pages = ((image.phys_end - image.phys_start) / PAGE_SIZE) as usize;
+    let allocate_type = if !pie {
+        if randomize {
+            log::warn!("The kernel is not position independent, KASLR is disabled");
+        }
+        AllocateType::Address(image.phys_start)
+    } else if let Some(address) = randomize.then(|| pick_load_address(pages)).flatten() {
+        image.randomized = 1;
+        AllocateType::Address(address)
+    } else {
+        AllocateType::AnyPages
+    };
+    let mut result = boot::allocate_pages(allocate_type, MemoryType::LOADER_CODE, pages);
+    if result.is_err() && image.randomized != 0 {
+        // Someone took the picked range since the memory map was read. Booting without
+        // KASLR beats not booting.
+        log::warn!("Could not load the kernel at its random address, KASLR is disabled");
+        image.randomized = 0;
+        result = boot::allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_CODE, pages);
+    }
+    let allocation = result.map_err(|_| LoadError::Allocation {
+        phys: (!pie).then_some(image.phys_start),
+        pages,
+    })?;
+    let dest = allocation.as_ptr();
+    unsafe { core::ptr::write_bytes(dest, 0, pages * PAGE_SIZE as usize) };
+
+    // Zero for executables. A PIE kernel runs at the same virtual and physical address
+    // until it switches to its own page tables, so both move by the same amount.
+    let load_bias = (dest as u64).wrapping_sub(image.phys_start);
+    image.slide = load_bias;
+    image.phys_start = image.phys_start.wrapping_add(load_bias);
+    image.phys_end = image.phys_end.wrapping_add(load_bias);
+    image.virt_start = image.virt_start.wrapping_add(load_bias);
+    image.virt_end = image.virt_end.wrapping_add(load_bias);
+    for segment in &mut image.segments[..image.segment_count as usize] {
+        segment.phys_start = segment.phys_start.wrapping_add(load_bias);
+        segment.virt_start = segment.virt_start.wrapping_add(load_bias);
+    }
+
+    log::info!(
+        "Kernel slide 0x{:x}{}",
+        image.slide,
+        if image.randomized != 0 {
+            " (KASLR)"
+        } else {
+            ""
+        }
+    );
+    for segment in image.segments() {
+        log::info!(
+            "Kernel segment 0x{:x}-0x{:x} {} at 0x{:x}",
+            segment.virt_start,
+            segment.virt_start + segment.size,
+            segment.flags,
+            segment.phys_start
+        );
+    }
+
+    let entry_point = copy_segments(&elf, &bytes, pie, load_bias)
+        .and_then(|()| {
+            if pie {
+                apply_relocations(&elf, load_bias)
+            } else {
+                Ok(())
+            }
+        })
+        .and_then(|()| {
+            let entry_virt = elf.header.pt2.entry_point().wrapping_add(load_bias);
+            image
+                .segments()
+                .iter()
+                .find(|segment| {
+                    (segment.virt_start..segment.virt_start + segment.size).contains(&entry_virt)
+                })
+                .map(|segment| segment.phys_start + (entry_virt - segment.virt_start))
+                .ok_or(LoadError::EntryOutsideImage(entry_virt))
+        });
+    let entry_point = match entry_point {
+        Ok(entry_point) => entry_point,
+        Err(err) => {
+            // A fallback kernel may need the very same address.
+            let _ = unsafe { boot::free_pages(allocation, pages) };
+            return Err(err);
+        }
+    };
+    let entry: KernelEntry = unsafe { core::mem::transmute(entry_point as usize) };
+
+    Ok(LoadedKernel {
+        entry_point,
+        entry,
+        image,
+        symbols: read_symbols(&elf),
+    })
+}
 
+/// Copies the file contents of every `PT_LOAD` segment into the zeroed image.
+fn copy_segments(elf: &ElfFile, bytes: &[u8], pie: bool, load_bias: u64) -> Result<(), LoadError> {
+    for ph in elf.program_iter() {
+        if ph.get_type() != Ok(program::Type::Load) || ph.mem_size() == 0 {
+            continue;
+        }
+        let data = bytes
+            .get(ph.offset() as usize..(ph.offset() + ph.file_size()) as usize)
+            .ok_or(LoadError::Parse("segment extends past the end of the file"))?;
+        let phys = if pie {
+            ph.virtual_addr()
+        } else {
+            ph.physical_addr()
+        }
+        .wrapping_add(load_bias);
         unsafe {
-            core::ptr::copy_nonoverlapping(
-                bytes[file_offset..].as_ptr(),
-                dest_ptr.add(page_offset),
-                file_size,
-            );
-
-            if mem_size > file_size {
-                core::ptr::write_bytes(dest_ptr.add(page_offset + file_size), 0, mem_size - file_size);
+            core::ptr::copy_nonoverlapping(data.as_ptr(), phys as *mut u8, data.len());
+        }
+    }
+    Ok(())
+}
+
+/// Applies the `DT_RELA` relocations of a PIE kernel that was loaded `load_bias`
+/// bytes above its link address.
+///
+/// The kernel is statically linked, so `R_X86_64_RELATIVE` is the only relocation
+/// it should ever need. Anything else means it was built the wrong way.
+fn apply_relocations(elf: &ElfFile, load_bias: u64) -> Result<(), LoadError> {
+    let Some(dynamic) = elf
+        .program_iter()
+        .find(|ph| ph.get_type() == Ok(program::Type::Dynamic))
+    else {
+        return Ok(());
+    };
+    let SegmentData::Dynamic64(entries) = dynamic.get_data(elf).map_err(LoadError::Parse)? else {
+        return Err(LoadError::Parse("malformed dynamic segment"));
+    };
+
+    let mut rela = 0;
+    let mut rela_size = 0;
+    let mut rela_entry_size = size_of::<Rela<u64>>() as u64;
+    for entry in entries {
+        match entry.get_tag().map_err(LoadError::Parse)? {
+            Tag::Null => break,
+            Tag::Rela => rela = entry.get_ptr().map_err(LoadError::Parse)?,
+            Tag::RelaSize => rela_size = entry.get_val().map_err(LoadError::Parse)?,
+            Tag::RelaEnt => rela_entry_size = entry.get_val().map_err(LoadError::Parse)?,
+            Tag::Rel | Tag::Relr | Tag::JmpRel => {
+                return Err(LoadError::Parse(
+                    "only DT_RELA relocations are supported, relink the kernel",
+                ));
             }
+            _ => {}
         }
     }
 
-    let entry_point = (KERNEL_LOAD_BASE + elf.header.pt2.entry_point()) as usize;
-    let kernel_entry: unsafe extern "C" fn() -> ! = unsafe { core::mem::transmute(entry_point) };
+    if rela == 0 || rela_size == 0 {
+        return Ok(());
+    }
+    if rela_entry_size != size_of::<Rela<u64>>() as u64 {
+        return Err(LoadError::Parse("unexpected DT_RELAENT"));
+    }
+
+    // .rela.dyn is part of a loaded segment, so read it from where it was copied.
+    let relocations = unsafe {
+        core::slice::from_raw_parts(
+            rela.wrapping_add(load_bias) as *const Rela<u64>,
+            (rela_size / rela_entry_size) as usize,
+        )
+    };
+    for relocation in relocations {
+        match relocation.get_type() {
+            R_X86_64_NONE => {}
+            R_X86_64_RELATIVE => unsafe {
+                let target = relocation.get_offset().wrapping_add(load_bias) as *mut u64;
+                target.write_unaligned(relocation.get_addend().wrapping_add(load_bias));
+            },
+            kind => {
+                return Err(LoadError::UnsupportedRelocation {
+                    kind,
+                    offset: relocation.get_offset(),
+                });
+            }
+        }
+    }
 
-    (entry_point, kernel_entry)
+    log::info!(
+        "Applied {} relocations for a load bias of 0x{:x}",
+        relocations.len(),
+        load_bias
+    );
+    Ok(())
 }
@@ -1,46 +1,129 @@
+use alloc::vec::Vec;
+use boyinfo::{FramebufferInfo, PixelFormat, PixelMasks};
 use uefi::{
     boot::{get_handle_for_protocol, open_protocol_exclusive},
-    proto::console::gop::{self, GraphicsOutput},
+    proto::console::gop::{self, GraphicsOutput, Mode, ModeInfo},
 };
-use log::info;
-
-#[repr(C)]
-#[derive(Debug)]
-pub struct FramebufferInfo {
-    pub address: u64,
-    pub size: usize,
-    pub width: usize,
-    pub height: usize,
-    pub stride: usize,
-    pub format: u32, // You can define enum values for known formats
+use log::{info, warn};
+
+fn pixel_format(info: &ModeInfo) -> PixelFormat {
+    match info.pixel_format() {
+        gop::PixelFormat::Rgb => PixelFormat::Rgb,
+        gop::PixelFormat::Bgr => PixelFormat::Bgr,
+        // Without masks there is no telling where the colors are, so it is as good as
+        // no framebuffer.
+        gop::PixelFormat::Bitmask => match info.pixel_bitmask() {
+            Some(mask) => PixelFormat::Bitmask(PixelMasks {
+                red: mask.red,
+                green: mask.green,
+                blue: mask.blue,
+                reserved: mask.reserved,
+            }),
+            None => PixelFormat::BltOnly,
+        },
+        gop::PixelFormat::BltOnly => PixelFormat::BltOnly,
+    }
+}
+
+fn area(mode: &Mode) -> usize {
+    let (width, height) = mode.info().resolution();
+    width * height
+}
+
+/// Picks the mode to run the kernel in among the 32-bpp ones.
+///
+/// With a `resolution` that is an exact match, or else the largest mode fitting
+/// inside it. Without one, or if nothing fits, the largest mode there is.
+fn choose_mode(modes: &[Mode], resolution: Option<(usize, usize)>) -> Option<Mode> {
+    let usable = || {
+        modes
+            .iter()
+            .filter(|mode| pixel_format(mode.info()).bits_per_pixel() == Some(32))
+    };
+
+    if let Some((width, height)) = resolution {
+        if let Some(mode) = usable().find(|mode| mode.info().resolution() == (width, height)) {
+            return Some(*mode);
+        }
+        let fitting = usable()
+            .filter(|mode| {
+                let (w, h) = mode.info().resolution();
+                w <= width && h <= height
+            })
+            .max_by_key(|mode| area(mode));
+        match fitting {
+            Some(mode) => {
+                let (w, h) = mode.info().resolution();
+                warn!("No {}x{} mode, using {}x{} instead", width, height, w, h);
+                return Some(*mode);
+            }
+            None => warn!("No mode fits in {}x{}, using the largest one", width, height),
+        }
+    }
+
+    usable().max_by_key(|mode| area(mode)).copied()
 }
 
-pub fn initialize_framebuffer() -> FramebufferInfo {
+/// Switches to the best GOP mode for `resolution` and describes its framebuffer.
+pub fn initialize_framebuffer(resolution: Option<(usize, usize)>) -> FramebufferInfo {
     let gop_handle = get_handle_for_protocol::<GraphicsOutput>().unwrap();
     let mut gop_protocol = open_protocol_exclusive::<GraphicsOutput>(gop_handle).unwrap();
     let gop = gop_protocol.get_mut().unwrap();
+
+    let modes: Vec<Mode> = gop.modes().collect();
+    info!("{} GOP modes available:", modes.len());
+    for (index, mode) in modes.iter().enumerate() {
+        let mode_info = mode.info();
+        let (width, height) = mode_info.resolution();
+        info!(
+            "  {:>2}: {}x{} {:?} {} bpp, stride {}",
+            index,
+            width,
+            height,
+            mode_info.pixel_format(),
+            pixel_format(mode_info).bits_per_pixel().unwrap_or(0),
+            mode_info.stride()
+        );
+    }
+
+    match choose_mode(&modes, resolution) {
+        Some(mode) if *mode.info() != gop.current_mode_info() => {
+            if let Err(err) = gop.set_mode(&mode) {
+                warn!("Failed to switch GOP mode: {:?}", err.status());
+            }
+        }
+        Some(_) => {}
+        None => warn!("No 32-bpp GOP mode, keeping the current one"),
+    }
+
     let mode_info = gop.current_mode_info();
     let resolution = mode_info.resolution();
     let stride = mode_info.stride();
-    let pixel_format = mode_info.pixel_format();
-
-    let mut gop_buffer = gop.frame_buffer();
-    let gop_buffer_first_byte = gop_buffer.as_mut_ptr() as usize;
-
-    info!("Framebuffer address: 0x{:x}", gop_buffer_first_byte);
-    info!("Framebuffer size: {} bytes", gop_buffer.size());
+    let format = pixel_format(&mode_info);
+    info!(
+        "Using GOP mode {}x{} {:?}",
+        resolution.0, resolution.1, format
+    );
 
-    FramebufferInfo {
-        address: gop_buffer.as_mut_ptr() as u64,
-        size: gop_buffer.size(),
+    let mut framebuffer = FramebufferInfo {
+        address: 0,
+        size: 0,
         width: resolution.0,
         height: resolution.1,
         stride,
-        format: match pixel_format {
-            gop::PixelFormat::Rgb => 0,
-            gop::PixelFormat::Bgr => 1,
-            gop::PixelFormat::Bitmask => 2,
-            gop::PixelFormat::BltOnly => 3,
-        },
+        format,
+    };
+    if format == PixelFormat::BltOnly {
+        warn!("The firmware only offers BltOnly graphics, the kernel will only log to serial");
+        return framebuffer;
     }
+
+    let mut gop_buffer = gop.frame_buffer();
+    framebuffer.address = gop_buffer.as_mut_ptr() as u64;
+    framebuffer.size = gop_buffer.size();
+
+    info!("Framebuffer address: 0x{:x}", framebuffer.address);
+    info!("Framebuffer size: {} bytes", framebuffer.size);
+
+    framebuffer
 }
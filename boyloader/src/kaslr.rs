@@ -0,0 +1,104 @@
+use core::arch::x86_64::{__cpuid, _rdtsc};
+
+use uefi::{
+    boot::{self, MemoryType},
+    mem::memory_map::MemoryMap,
+    proto::rng::Rng,
+};
+
+/// Kernel load addresses are multiples of this, so 2 MiB pages stay usable.
+const KASLR_ALIGN: u64 = 0x20_0000;
+/// Never place the kernel below this, low memory is kept for legacy uses.
+const KASLR_MIN: u64 = 0x100_0000;
+const PAGE_SIZE: u64 = 0x1000;
+
+/// Random bits from `EFI_RNG_PROTOCOL`, falling back to RDRAND and then to the TSC.
+pub fn random_u64() -> u64 {
+    if let Some(value) = firmware_random() {
+        return value;
+    }
+    if let Some(value) = rdrand() {
+        log::warn!("No EFI_RNG_PROTOCOL, using RDRAND for KASLR");
+        return value;
+    }
+    log::warn!("No EFI_RNG_PROTOCOL or RDRAND, using the TSC for KASLR");
+    // The low bits of the TSC are the only ones that differ between boots.
+    let tsc = unsafe { _rdtsc() };
+    tsc.wrapping_mul(0x9E37_79B9_7F4A_7C15).rotate_left(32) ^ tsc
+}
+
+fn firmware_random() -> Option<u64> {
+    let handle = boot::get_handle_for_protocol::<Rng>().ok()?;
+    let mut rng = boot::open_protocol_exclusive::<Rng>(handle).ok()?;
+    let mut bytes = [0u8; 8];
+    rng.get_rng(None, &mut bytes).ok()?;
+    Some(u64::from_le_bytes(bytes))
+}
+
+fn rdrand() -> Option<u64> {
+    // CPUID.01H:ECX.RDRAND[bit 30]
+    if __cpuid(1).ecx & (1 << 30) == 0 {
+        return None;
+    }
+    // RDRAND may run dry for a moment, Intel recommends retrying ten times.
+    for _ in 0..10 {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            core::arch::asm!(
+                "rdrand {value}",
+                "setc {ok}",
+                value = out(reg) value,
+                ok = out(reg_byte) ok,
+                options(nomem, nostack)
+            );
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Picks a random [`KASLR_ALIGN`]ed address with `pages` of free memory behind it.
+///
+/// Every aligned slot in conventional memory is equally likely. Returns `None` if
+/// there is no room at all.
+pub fn pick_load_address(pages: usize) -> Option<u64> {
+    let size = pages as u64 * PAGE_SIZE;
+    let memory_map = boot::memory_map(MemoryType::LOADER_DATA).ok()?;
+
+    let slots = |start: u64, end: u64| -> u64 {
+        let first = start.max(KASLR_MIN).next_multiple_of(KASLR_ALIGN);
+        match end.checked_sub(size) {
+            Some(last) if last >= first => (last - first) / KASLR_ALIGN + 1,
+            _ => 0,
+        }
+    };
+    let regions = || {
+        memory_map
+            .entries()
+            .filter(|desc| desc.ty == MemoryType::CONVENTIONAL)
+            .map(|desc| {
+                (
+                    desc.phys_start,
+                    desc.phys_start + desc.page_count * PAGE_SIZE,
+                )
+            })
+    };
+
+    let total: u64 = regions().map(|(start, end)| slots(start, end)).sum();
+    if total == 0 {
+        return None;
+    }
+
+    let mut slot = random_u64() % total;
+    for (start, end) in regions() {
+        let count = slots(start, end);
+        if slot < count {
+            return Some(start.max(KASLR_MIN).next_multiple_of(KASLR_ALIGN) + slot * KASLR_ALIGN);
+        }
+        slot -= count;
+    }
+    None
+}
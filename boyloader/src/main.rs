@@ -3,15 +3,21 @@
 
 extern crate alloc;
 
+mod boot_info;
+mod config;
 mod elf_garbage;
 mod files;
 mod framebuffer;
+mod kaslr;
+mod menu;
+mod symbols;
 
-use core::arch::asm;
-
+use boot_info::build_boot_info;
+use config::{BootConfig, load_config};
 use elf_garbage::load_kernel;
-use framebuffer::{initialize_framebuffer, FramebufferInfo};
-use log::info;
+use framebuffer::initialize_framebuffer;
+use menu::run_menu;
+use log::{error, info, warn};
 use uefi::{
     boot::{get_handle_for_protocol, open_protocol_exclusive},
     prelude::*,
@@ -27,31 +33,52 @@ fn main() -> Status {
     let mut output = open_protocol_exclusive::<Output>(handle).unwrap();
     output.clear().expect("Failed to clear screen");
     info!("boyloader online!");
-    //info!("Launching in 3 seconds...");
-    //boot::stall(3_000_000);
     output.clear().expect("Failed to clear screen");
-    boot_system();
+    // Protocols can't be closed after exiting boot services, so let go of it now.
+    drop(output);
+
+    let config = load_config();
+    log::set_max_level(config.log_level);
+
+    let selection = run_menu(&config);
+    let mut status = boot_system(&config, selection.entry, &selection.cmdline);
+    if let Some(fallback) = config.fallback_entry.filter(|&index| index != selection.entry) {
+        let entry = &config.entries[fallback];
+        warn!("Trying fallback entry \"{}\"", entry.name);
+        status = boot_system(&config, fallback, &entry.cmdline);
+    }
 
-    Status::SUCCESS
+    // Leave the errors on screen for a bit before going back to the firmware.
+    boot::stall(10_000_000);
+    status
 }
 
-pub fn boot_system() {
-    let (entry_point, kernel_entry) = load_kernel("\\EFI\\BOOT\\boykernel");
+/// Loads and jumps to the kernel of `config.entries[entry]`, only returning if it
+/// could not be loaded.
+pub fn boot_system(config: &BootConfig, entry: usize, cmdline: &str) -> Status {
+    let entry = &config.entries[entry];
+    info!("Booting \"{}\" from {}", entry.name, entry.kernel);
 
-    info!("Kernel entry point: 0x{:x}", kernel_entry as usize);
+    // `nokaslr` works like on Linux, for when addresses need to match between boots.
+    let randomize = config.kaslr && !cmdline.split_whitespace().any(|arg| arg == "nokaslr");
 
-    let framebuffer_info = initialize_framebuffer();
+    let kernel = match load_kernel(&entry.kernel, randomize) {
+        Ok(kernel) => kernel,
+        Err(err) => {
+            error!("Failed to load the kernel: {}", err);
+            return Status::LOAD_ERROR;
+        }
+    };
+
+    info!("Kernel entry point: 0x{:x}", kernel.entry_point);
+
+    let framebuffer_info = initialize_framebuffer(config.resolution);
     info!("Framebuffer info: {:?}", framebuffer_info);
 
-    info!("Jumping to kernel entry point at 0x{:x}", entry_point);
+    let pending = build_boot_info(framebuffer_info, kernel.image, &kernel.symbols, cmdline);
 
-    unsafe {
-        let fb_ptr = &framebuffer_info as *const FramebufferInfo;
-        asm!(
-            "mov rdi, {0}",
-            in(reg) fb_ptr,
-            options(nostack)
-        );
-        kernel_entry();
-    }
+    info!("Exiting boot services and jumping to kernel entry point at 0x{:x}", kernel.entry_point);
+
+    let boot_info = pending.exit_boot_services();
+    (kernel.entry)(boot_info);
 }
@@ -0,0 +1,167 @@
+use alloc::string::String;
+use core::fmt::Write;
+
+use uefi::{
+    boot::{self, EventType, TimerTrigger, Tpl},
+    proto::console::text::{Color, Key, Output, ScanCode},
+    system,
+};
+
+use crate::config::BootConfig;
+
+/// What to boot, as picked in the menu.
+pub struct Selection {
+    /// Index into [`BootConfig::entries`].
+    pub entry: usize,
+    /// Command line for this boot only, possibly edited by the user.
+    pub cmdline: String,
+}
+
+const ENTER: char = '\r';
+const BACKSPACE: char = '\u{8}';
+/// One second in the 100 ns units of UEFI timers.
+const ONE_SECOND: u64 = 10_000_000;
+
+/// Waits for the next key press. While a `countdown` runs it also wakes up after a
+/// second and returns `None`, so the caller can tick it down and redraw.
+fn next_key(countdown: bool) -> Option<Key> {
+    loop {
+        if let Ok(Some(key)) = system::with_stdin(|input| input.read_key()) {
+            return Some(key);
+        }
+
+        let key_event = system::with_stdin(|input| input.wait_for_key_event())?;
+        if !countdown {
+            let _ = boot::wait_for_event(&mut [key_event]);
+            continue;
+        }
+
+        let timer = unsafe { boot::create_event(EventType::TIMER, Tpl::CALLBACK, None, None) }
+            .expect("Failed to create the menu timer");
+        boot::set_timer(&timer, TimerTrigger::Relative(ONE_SECOND))
+            .expect("Failed to arm the menu timer");
+        let mut events = [key_event, unsafe { timer.unsafe_clone() }];
+        let woke = boot::wait_for_event(&mut events);
+        let _ = boot::close_event(timer);
+        if woke == Ok(1) {
+            return None;
+        }
+    }
+}
+
+fn draw(
+    out: &mut Output,
+    config: &BootConfig,
+    selected: usize,
+    cmdline: &str,
+    remaining: Option<u64>,
+) {
+    let _ = out.set_color(Color::White, Color::Black);
+    let _ = out.clear();
+    let _ = writeln!(out, "boyloader\n");
+
+    for (index, entry) in config.entries.iter().enumerate() {
+        if index == selected {
+            let _ = out.set_color(Color::Black, Color::LightGray);
+        }
+        let _ = write!(out, "  {:<40}", entry.name);
+        let _ = out.set_color(Color::White, Color::Black);
+        let _ = writeln!(out, "  {}", entry.kernel);
+    }
+
+    let _ = writeln!(out, "\ncmdline: {}", cmdline);
+    let _ = writeln!(
+        out,
+        "\nUp/Down: select   Enter: boot   e: edit command line"
+    );
+    if let Some(seconds) = remaining {
+        let _ = writeln!(
+            out,
+            "Booting \"{}\" in {} s",
+            config.entries[selected].name, seconds
+        );
+    }
+}
+
+/// Lets the user edit `cmdline` on the bottom line. Escape throws the edit away.
+fn edit_cmdline(cmdline: &str) -> Option<String> {
+    let mut edited = String::from(cmdline);
+    loop {
+        system::with_stdout(|out| {
+            let _ = write!(out, "\rcmdline: {} \u{8}", edited);
+        });
+        match next_key(false)? {
+            Key::Printable(c) => match char::from(c) {
+                ENTER => return Some(edited),
+                BACKSPACE => {
+                    edited.pop();
+                    // Wipe the character that was just removed.
+                    system::with_stdout(|out| {
+                        let _ = write!(out, "\rcmdline: {}  ", edited);
+                    });
+                }
+                c if !c.is_control() => edited.push(c),
+                _ => {}
+            },
+            Key::Special(ScanCode::ESCAPE) => return None,
+            Key::Special(_) => {}
+        }
+    }
+}
+
+/// Shows the boot menu and returns what to boot.
+///
+/// The default entry is booted when `config.timeout` runs out, any key stops the
+/// countdown. With a timeout of zero the menu is skipped entirely.
+pub fn run_menu(config: &BootConfig) -> Selection {
+    let mut selected = config.default_entry;
+    let mut cmdline = config.default_entry().cmdline.clone();
+    if config.timeout == 0 {
+        return Selection {
+            entry: selected,
+            cmdline,
+        };
+    }
+
+    let mut remaining = Some(config.timeout);
+    loop {
+        system::with_stdout(|out| draw(out, config, selected, &cmdline, remaining));
+
+        let Some(key) = next_key(remaining.is_some()) else {
+            remaining = remaining.map(|seconds| seconds - 1);
+            if remaining == Some(0) {
+                break;
+            }
+            continue;
+        };
+        remaining = None;
+
+        let previous = selected;
+        match key {
+            Key::Special(ScanCode::UP) => selected = selected.saturating_sub(1),
+            Key::Special(ScanCode::DOWN) => selected = (selected + 1).min(config.entries.len() - 1),
+            Key::Printable(c) if char::from(c) == ENTER => break,
+            Key::Printable(c) if char::from(c) == 'e' => {
+                system::with_stdout(|out| {
+                    let _ = writeln!(out, "\nEnter: accept   Esc: cancel");
+                });
+                if let Some(edited) = edit_cmdline(&cmdline) {
+                    cmdline = edited;
+                }
+            }
+            _ => {}
+        }
+        // Edits only apply to the entry they were made on.
+        if selected != previous {
+            cmdline = config.entries[selected].cmdline.clone();
+        }
+    }
+
+    system::with_stdout(|out| {
+        let _ = out.clear();
+    });
+    Selection {
+        entry: selected,
+        cmdline,
+    }
+}
@@ -0,0 +1,65 @@
+use alloc::{format, string::String, vec::Vec};
+use xmas_elf::{
+    ElfFile,
+    sections::{SHF_EXECINSTR, SectionData},
+    symbol_table::{Binding, Entry, Type},
+};
+
+/// A function of the kernel, with its name already demangled.
+pub struct Symbol {
+    /// Link address, as in the ELF file.
+    pub address: u64,
+    pub size: u64,
+    pub name: String,
+}
+
+/// Whether `entry` names code: a function, or a global label `global_asm!` defined in
+/// an executable section, which has no type.
+fn is_code(elf: &ElfFile, entry: &impl Entry) -> bool {
+    if entry.value() == 0 {
+        return false;
+    }
+    match entry.get_type() {
+        Ok(Type::Func) => true,
+        Ok(Type::NoType) => {
+            entry.get_binding() == Ok(Binding::Global)
+                && elf
+                    .section_header(entry.shndx())
+                    .is_ok_and(|section| section.flags() & SHF_EXECINSTR != 0)
+        }
+        _ => false,
+    }
+}
+
+/// Reads the code symbols of the kernel ELF's `.symtab`, sorted by address.
+///
+/// Returns nothing for a stripped kernel, backtraces then only show addresses.
+pub fn read_symbols(elf: &ElfFile) -> Vec<Symbol> {
+    let Some(SectionData::SymbolTable64(entries)) = elf
+        .find_section_by_name(".symtab")
+        .and_then(|section| section.get_data(elf).ok())
+    else {
+        log::warn!("The kernel has no symbol table, backtraces will not show names");
+        return Vec::new();
+    };
+
+    let mut symbols: Vec<Symbol> = entries
+        .iter()
+        .filter(|entry| is_code(elf, *entry))
+        .filter_map(|entry| {
+            let name = entry.get_name(elf).ok()?;
+            Some(Symbol {
+                address: entry.value(),
+                size: entry.size(),
+                // The alternate format leaves out the hash suffix.
+                name: format!("{:#}", rustc_demangle::demangle(name)),
+            })
+        })
+        .collect();
+    // A function and an assembly label at the same address keep the function.
+    symbols.sort_unstable_by_key(|symbol| (symbol.address, symbol.size == 0));
+    symbols.dedup_by_key(|symbol| symbol.address);
+
+    log::info!("Loaded {} kernel symbols", symbols.len());
+    symbols
+}